@@ -0,0 +1,6 @@
+-- Remembers the preset definitions thumbnails were last generated with
+CREATE TABLE IF NOT EXISTS thumbnail_presets
+(
+    name        TEXT PRIMARY KEY    NOT NULL,
+    definition  TEXT                NOT NULL
+);
@@ -0,0 +1,3 @@
+-- MIME type and original file name of each upload, used when serving it
+ALTER TABLE images ADD COLUMN mime_type TEXT DEFAULT 'image/jpeg' NOT NULL;
+ALTER TABLE images ADD COLUMN file_name TEXT;
@@ -0,0 +1,10 @@
+-- Originals, variants and tiles when STORAGE_BACKEND=sqlite
+CREATE TABLE IF NOT EXISTS blobs
+(
+    id            INTEGER PRIMARY KEY NOT NULL,
+    key           TEXT UNIQUE         NOT NULL,
+    data          BLOB                NOT NULL,
+    len           INTEGER             NOT NULL,
+    -- Milliseconds since the Unix epoch
+    last_modified INTEGER             NOT NULL
+);
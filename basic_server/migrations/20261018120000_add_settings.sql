@@ -0,0 +1,6 @@
+-- Server state that must survive restarts, such as the storage layout in use
+CREATE TABLE IF NOT EXISTS settings
+(
+    name        TEXT PRIMARY KEY    NOT NULL,
+    value       TEXT                NOT NULL
+);
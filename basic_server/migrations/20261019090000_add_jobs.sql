@@ -0,0 +1,20 @@
+-- Background work such as thumbnail generation, persisted so that a crash
+-- or restart does not lose it
+CREATE TABLE IF NOT EXISTS jobs
+(
+    id          INTEGER PRIMARY KEY NOT NULL,
+    image_id    INTEGER             NOT NULL,
+    -- Comma-separated preset names; NULL renders every configured preset
+    presets     TEXT,
+    -- queued, running, done or failed
+    state       TEXT                NOT NULL DEFAULT 'queued',
+    attempts    INTEGER             NOT NULL DEFAULT 0,
+    last_error  TEXT,
+    -- Unix seconds; a queued job is not picked up before this
+    run_after   INTEGER             NOT NULL,
+    started_at  INTEGER,
+    created_at  INTEGER             NOT NULL,
+    updated_at  INTEGER             NOT NULL
+);
+CREATE INDEX IF NOT EXISTS jobs_state_run_after ON jobs (state, run_after);
+CREATE INDEX IF NOT EXISTS jobs_image_id ON jobs (image_id);
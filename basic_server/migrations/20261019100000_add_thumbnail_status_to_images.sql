@@ -0,0 +1,7 @@
+-- Replaces the `thumbnail` flag with a status, the last generation error and
+-- the version of the generator that produced the thumbnails
+ALTER TABLE images ADD COLUMN thumbnail_status TEXT DEFAULT 'pending' NOT NULL;
+ALTER TABLE images ADD COLUMN thumbnail_error TEXT;
+ALTER TABLE images ADD COLUMN thumbnail_version INTEGER;
+UPDATE images SET thumbnail_status = 'ready' WHERE thumbnail = 1;
+ALTER TABLE images DROP COLUMN thumbnail;
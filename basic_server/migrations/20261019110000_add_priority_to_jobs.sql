@@ -0,0 +1,3 @@
+-- Jobs a client is waiting for (1, e.g. fresh uploads) are claimed before
+-- backfill work (0)
+ALTER TABLE jobs ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
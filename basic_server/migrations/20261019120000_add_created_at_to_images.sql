@@ -0,0 +1,3 @@
+-- Upload time in Unix seconds, so background reconciliation can leave
+-- uploads in progress alone; NULL for images uploaded before it was recorded
+ALTER TABLE images ADD COLUMN created_at INTEGER;
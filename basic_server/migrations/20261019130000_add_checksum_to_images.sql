@@ -0,0 +1,5 @@
+-- Size and SHA-256 of each original, computed while the upload streams in;
+-- NULL for images uploaded before they were recorded
+ALTER TABLE images ADD COLUMN sha256 TEXT;
+ALTER TABLE images ADD COLUMN size INTEGER;
+CREATE INDEX images_sha256 ON images (sha256);
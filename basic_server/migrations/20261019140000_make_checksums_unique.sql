@@ -0,0 +1,8 @@
+-- One image per content, so two uploads of the same file racing past the
+-- duplicate check cannot both be recorded. Copies stored before uploads were
+-- deduplicated keep their file but forget their checksum.
+UPDATE images
+SET sha256 = NULL
+WHERE id > (SELECT MIN(id) FROM images AS first WHERE first.sha256 = images.sha256);
+DROP INDEX images_sha256;
+CREATE UNIQUE INDEX images_sha256 ON images (sha256) WHERE sha256 IS NOT NULL;
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use thumbnail::{Fit, ThumbnailFormat, ThumbnailOptions};
+
+use crate::service::url_signer::UrlSigner;
+use crate::storage::s3::{S3Config, DEFAULT_PART_SIZE, MAX_PARTS, MAX_PART_SIZE, MIN_PART_SIZE};
+
+/// Sizes `/thumbnails/:id?w=..&h=..` may be asked for unless overridden by
+/// `THUMBNAIL_SIZES`. Restricting them keeps clients from filling the disk
+/// with one cached variant per pixel size.
+const DEFAULT_THUMBNAIL_SIZES: &[(u32, u32)] = &[(100, 100), (320, 240), (640, 480), (1280, 720)];
+
+/// Preset served by `/thumbnails/:id` without a preset name.
+pub const DEFAULT_PRESET: &str = "thumbnail";
+
+/// Presets used unless `THUMBNAIL_PRESETS` is set, in the same
+/// `name:WxH:fit:format:quality` syntax.
+const DEFAULT_PRESETS: &str = "thumbnail:100x100:contain:jpg:75;\
+    avatar:64x64:cover:webp:100;\
+    card:320x240:cover:jpg:85;\
+    hero:1280x720:contain:jpg:85";
+
+/// Default `Cache-Control` values. Responses carry ETags, so clients revalidate
+/// cheaply once these expire.
+const DEFAULT_CACHE_CONTROL_ORIGINALS: &str = "public, max-age=3600";
+const DEFAULT_CACHE_CONTROL_VARIANTS: &str = "public, max-age=86400";
+
+/// Where originals and variants are stored unless `STORAGE_ROOT` is set.
+const DEFAULT_STORAGE_ROOT: &str = "../images/";
+
+/// Thumbnail jobs processed at the same time unless `JOB_WORKERS` is set.
+const DEFAULT_JOB_WORKERS: usize = 2;
+/// Image operations (decode, resize, encode) running at the same time unless
+/// `IMAGE_WORKERS` is set: one per CPU core.
+fn default_image_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1)
+}
+/// Pause between background reconciliations unless `BACKFILL_INTERVAL`
+/// (seconds) is set.
+const DEFAULT_BACKFILL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How old orphaned blobs and stale variants must be before garbage
+/// collection deletes them, unless `GC_GRACE` (seconds) is set.
+const DEFAULT_GC_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Largest request body accepted by the upload routes unless
+/// `MAX_UPLOAD_SIZE` (bytes) is set.
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// Runs of a job before it is marked failed, unless `JOB_MAX_ATTEMPTS` is set.
+const DEFAULT_JOB_MAX_ATTEMPTS: u32 = 5;
+
+/// Region used for request signing unless `S3_REGION` is set; MinIO and most
+/// other stand-ins accept any value.
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Where originals and variants live, chosen by `STORAGE_BACKEND`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// Directory on local disk (`STORAGE_ROOT`).
+    Local(PathBuf),
+    /// S3-compatible bucket (`S3_*` variables).
+    S3(S3Config),
+    /// `blobs` table of a SQLite database, by default the one at `DATABASE_URL`
+    /// (`STORAGE_DATABASE`).
+    Sqlite(PathBuf),
+}
+
+/// A named thumbnail definition, served at `/thumbnails/:id/:name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preset {
+    pub name: String,
+    pub options: ThumbnailOptions,
+}
+
+impl Preset {
+    /// Canonical form of the definition, stored to notice when it changes.
+    pub fn definition(&self) -> String {
+        let options = &self.options;
+        format!(
+            "{}x{}:{}:{}:{}",
+            options.width,
+            options.height,
+            options.fit.as_str(),
+            options.format.extension(),
+            options.quality
+        )
+    }
+}
+
+/// Server settings read from the environment (and `.env`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub thumbnail_sizes: Vec<(u32, u32)>,
+    pub presets: Vec<Preset>,
+    /// Set from `URL_SIGNING_KEY`; image and thumbnail URLs must then be signed.
+    pub url_signer: Option<UrlSigner>,
+    /// `Cache-Control` of original images (`CACHE_CONTROL_ORIGINALS`).
+    pub cache_control_originals: String,
+    /// `Cache-Control` of thumbnails and tiles (`CACHE_CONTROL_VARIANTS`).
+    pub cache_control_variants: String,
+    /// Blob store of originals and variants (`STORAGE_BACKEND`).
+    pub storage: StorageBackend,
+    /// Concurrent background jobs (`JOB_WORKERS`).
+    pub job_workers: usize,
+    /// Runs of a job before it is given up (`JOB_MAX_ATTEMPTS`).
+    pub job_max_attempts: u32,
+    /// Image operations running at the same time, across requests and
+    /// jobs (`IMAGE_WORKERS`).
+    pub image_workers: usize,
+    /// Pause between reconciliations of the database with the blob store
+    /// (`BACKFILL_INTERVAL`, in seconds).
+    pub backfill_interval: Duration,
+    /// Minimum age of blobs deleted by garbage collection (`GC_GRACE`, in
+    /// seconds).
+    pub gc_grace: Duration,
+    /// Bearer token of the `/admin` routes and of editing, replacing and
+    /// deleting images (`ADMIN_TOKEN`); they are disabled without one.
+    pub admin_token: Option<AdminToken>,
+    /// Largest request body of an upload, in bytes (`MAX_UPLOAD_SIZE`).
+    pub max_upload_size: usize,
+    /// Where uploads are staged before they are stored (`UPLOAD_TMP_DIR`).
+    /// On the file system of `STORAGE_ROOT`, they are moved rather than copied.
+    pub upload_dir: PathBuf,
+    /// TrueType/OpenType font of contact sheet captions
+    /// (`CONTACT_SHEET_FONT`); captions are unavailable without one.
+    pub contact_sheet_font: Option<PathBuf>,
+}
+
+/// Secret guarding the admin routes; kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// Compares digests, so the time taken does not reveal how much of a
+    /// guess matched.
+    pub fn matches(&self, candidate: &str) -> bool {
+        Sha256::digest(self.0.as_bytes()) == Sha256::digest(candidate.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AdminToken(..)")
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            thumbnail_sizes: DEFAULT_THUMBNAIL_SIZES.to_vec(),
+            presets: parse_presets(DEFAULT_PRESETS).expect("default presets are valid"),
+            url_signer: None,
+            cache_control_originals: DEFAULT_CACHE_CONTROL_ORIGINALS.to_string(),
+            cache_control_variants: DEFAULT_CACHE_CONTROL_VARIANTS.to_string(),
+            storage: StorageBackend::Local(PathBuf::from(DEFAULT_STORAGE_ROOT)),
+            job_workers: DEFAULT_JOB_WORKERS,
+            job_max_attempts: DEFAULT_JOB_MAX_ATTEMPTS,
+            image_workers: default_image_workers(),
+            backfill_interval: DEFAULT_BACKFILL_INTERVAL,
+            gc_grace: DEFAULT_GC_GRACE,
+            admin_token: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            upload_dir: std::env::temp_dir(),
+            contact_sheet_font: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+
+        if let Ok(sizes) = std::env::var("THUMBNAIL_SIZES") {
+            config.thumbnail_sizes = parse_sizes(&sizes).context("invalid THUMBNAIL_SIZES")?;
+        }
+        if let Ok(presets) = std::env::var("THUMBNAIL_PRESETS") {
+            config.presets = parse_presets(&presets).context("invalid THUMBNAIL_PRESETS")?;
+        }
+        if let Ok(key) = std::env::var("URL_SIGNING_KEY") {
+            if key.is_empty() {
+                bail!("URL_SIGNING_KEY must not be empty");
+            }
+            config.url_signer = Some(UrlSigner::new(key));
+        }
+        if let Ok(cache_control) = std::env::var("CACHE_CONTROL_ORIGINALS") {
+            config.cache_control_originals =
+                parse_cache_control(cache_control).context("invalid CACHE_CONTROL_ORIGINALS")?;
+        }
+        if let Ok(cache_control) = std::env::var("CACHE_CONTROL_VARIANTS") {
+            config.cache_control_variants =
+                parse_cache_control(cache_control).context("invalid CACHE_CONTROL_VARIANTS")?;
+        }
+        config.storage = storage_from_env()?;
+        if let Ok(workers) = std::env::var("JOB_WORKERS") {
+            config.job_workers = parse_positive(&workers).context("invalid JOB_WORKERS")?;
+        }
+        if let Ok(attempts) = std::env::var("JOB_MAX_ATTEMPTS") {
+            config.job_max_attempts =
+                parse_positive(&attempts).context("invalid JOB_MAX_ATTEMPTS")?;
+        }
+        if let Ok(workers) = std::env::var("IMAGE_WORKERS") {
+            config.image_workers = parse_positive(&workers).context("invalid IMAGE_WORKERS")?;
+        }
+        if let Ok(interval) = std::env::var("BACKFILL_INTERVAL") {
+            let seconds = parse_positive(&interval).context("invalid BACKFILL_INTERVAL")?;
+            config.backfill_interval = Duration::from_secs(seconds);
+        }
+        if let Ok(grace) = std::env::var("GC_GRACE") {
+            let seconds = parse_positive(&grace).context("invalid GC_GRACE")?;
+            config.gc_grace = Duration::from_secs(seconds);
+        }
+        if let Ok(token) = std::env::var("ADMIN_TOKEN") {
+            if token.is_empty() {
+                bail!("ADMIN_TOKEN must not be empty");
+            }
+            config.admin_token = Some(AdminToken::new(token));
+        }
+        if let Ok(size) = std::env::var("MAX_UPLOAD_SIZE") {
+            config.max_upload_size = parse_positive(&size).context("invalid MAX_UPLOAD_SIZE")?;
+        }
+        if let StorageBackend::S3(s3) = &config.storage {
+            check_part_count(s3.part_size, config.max_upload_size)
+                .context("invalid S3_PART_SIZE")?;
+        }
+        if let Ok(dir) = std::env::var("UPLOAD_TMP_DIR") {
+            if dir.is_empty() {
+                bail!("UPLOAD_TMP_DIR must not be empty");
+            }
+            config.upload_dir = PathBuf::from(dir);
+        }
+        if let Ok(font) = std::env::var("CONTACT_SHEET_FONT") {
+            if font.is_empty() {
+                bail!("CONTACT_SHEET_FONT must not be empty");
+            }
+            config.contact_sheet_font = Some(PathBuf::from(font));
+        }
+
+        Ok(config)
+    }
+
+    pub fn is_allowed_size(&self, width: u32, height: u32) -> bool {
+        self.thumbnail_sizes.contains(&(width, height))
+    }
+
+    pub fn preset(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    /// Options of the `thumbnail` preset, or the crate defaults if it was not configured.
+    pub fn default_preset(&self) -> ThumbnailOptions {
+        self.preset(DEFAULT_PRESET)
+            .map(|preset| preset.options)
+            .unwrap_or_default()
+    }
+
+    /// Presets that are new or whose definition differs from `stored`
+    /// (name -> definition, as last generated).
+    pub fn changed_presets(&self, stored: &HashMap<String, String>) -> Vec<Preset> {
+        self.presets
+            .iter()
+            .filter(|preset| stored.get(&preset.name) != Some(&preset.definition()))
+            .cloned()
+            .collect()
+    }
+}
+
+// `STORAGE_BACKEND` is `local` (the default), `s3` or `sqlite`.
+fn storage_from_env() -> Result<StorageBackend> {
+    let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+    match backend.as_str() {
+        "local" => {
+            let root =
+                std::env::var("STORAGE_ROOT").unwrap_or_else(|_| DEFAULT_STORAGE_ROOT.to_string());
+            Ok(StorageBackend::Local(PathBuf::from(root)))
+        }
+        "s3" => {
+            let required = |name: &str| {
+                std::env::var(name)
+                    .ok()
+                    .filter(|value| !value.is_empty())
+                    .with_context(|| format!("{name} is required with STORAGE_BACKEND=s3"))
+            };
+            let path_style = match std::env::var("S3_PATH_STYLE").as_deref() {
+                Ok("true" | "1") => true,
+                Ok("false" | "0") | Err(_) => false,
+                Ok(other) => bail!("invalid S3_PATH_STYLE `{other}`, expected true or false"),
+            };
+            let part_size = match std::env::var("S3_PART_SIZE") {
+                Ok(size) => parse_part_size(&size).context("invalid S3_PART_SIZE")?,
+                Err(_) => DEFAULT_PART_SIZE,
+            };
+            Ok(StorageBackend::S3(S3Config {
+                endpoint: required("S3_ENDPOINT")?,
+                bucket: required("S3_BUCKET")?,
+                region: std::env::var("S3_REGION")
+                    .unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
+                access_key_id: required("S3_ACCESS_KEY_ID")?,
+                secret_access_key: required("S3_SECRET_ACCESS_KEY")?,
+                path_style,
+                part_size,
+            }))
+        }
+        "sqlite" => {
+            let path = match std::env::var("STORAGE_DATABASE") {
+                Ok(path) => PathBuf::from(path),
+                Err(_) => {
+                    let url = std::env::var("DATABASE_URL").context(
+                        "STORAGE_DATABASE or DATABASE_URL is required with STORAGE_BACKEND=sqlite",
+                    )?;
+                    sqlite_path(&url)?
+                }
+            };
+            Ok(StorageBackend::Sqlite(path))
+        }
+        other => bail!("unknown STORAGE_BACKEND `{other}`, expected local, s3 or sqlite"),
+    }
+}
+
+fn parse_positive<N: std::str::FromStr + Default + PartialEq>(value: &str) -> Result<N>
+where
+    N::Err: std::error::Error + Send + Sync + 'static,
+{
+    let number: N = value.trim().parse()?;
+    if number == N::default() {
+        bail!("must be at least 1");
+    }
+    Ok(number)
+}
+
+// Sizes S3 accepts for the parts of a multipart upload.
+fn parse_part_size(value: &str) -> Result<usize> {
+    let size: usize = value.trim().parse()?;
+    if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&size) {
+        bail!("must be between {MIN_PART_SIZE} and {MAX_PART_SIZE} bytes, got {size}");
+    }
+    Ok(size)
+}
+
+// The largest upload must fit into the parts S3 allows per object.
+fn check_part_count(part_size: usize, max_upload_size: usize) -> Result<()> {
+    let parts = max_upload_size.div_ceil(part_size.max(1));
+    if parts > MAX_PARTS {
+        bail!(
+            "{part_size} bytes per part would take {parts} parts for uploads of \
+             MAX_UPLOAD_SIZE ({max_upload_size} bytes), more than the {MAX_PARTS} S3 allows"
+        );
+    }
+    Ok(())
+}
+
+// File of a `sqlite:` database URL such as `sqlite:images.db?mode=rwc`.
+fn sqlite_path(url: &str) -> Result<PathBuf> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .with_context(|| format!("`{url}` is not a sqlite: URL"))?;
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() || path == ":memory:" {
+        bail!("`{url}` does not name a database file");
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Gives handlers access to the configuration, next to `ImageRepository`.
+pub trait ConfigProvider: Send + Sync + 'static {
+    fn config(&self) -> &Config;
+}
+
+// Only checks that the value can be sent as a header.
+fn parse_cache_control(value: String) -> Result<String> {
+    axum::http::HeaderValue::from_str(&value)?;
+    Ok(value)
+}
+
+// Parses a comma separated list such as `100x100,320x240`.
+fn parse_sizes(sizes: &str) -> Result<Vec<(u32, u32)>> {
+    let mut parsed = Vec::new();
+    for size in sizes.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((width, height)) = size.split_once('x') else {
+            bail!("expected WIDTHxHEIGHT, got `{size}`");
+        };
+        let width: u32 = width
+            .parse()
+            .with_context(|| format!("bad width in `{size}`"))?;
+        let height: u32 = height
+            .parse()
+            .with_context(|| format!("bad height in `{size}`"))?;
+        if width == 0 || height == 0 {
+            bail!("sizes must not be zero, got `{size}`");
+        }
+        parsed.push((width, height));
+    }
+    Ok(parsed)
+}
+
+// Parses `name:WxH:fit:format:quality` entries separated by `;`.
+fn parse_presets(presets: &str) -> Result<Vec<Preset>> {
+    let mut parsed: Vec<Preset> = Vec::new();
+    for entry in presets.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let parts: Vec<&str> = entry.split(':').map(str::trim).collect();
+        let [name, size, fit, format, quality] = parts[..] else {
+            bail!("expected name:WxH:fit:format:quality, got `{entry}`");
+        };
+
+        // Preset names end up in URLs and file names.
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            bail!("preset names may only contain a-z, 0-9 and '-', got `{name}`");
+        }
+        if parsed.iter().any(|preset| preset.name == name) {
+            bail!("preset `{name}` is defined twice");
+        }
+
+        let [(width, height)] = parse_sizes(size)?[..] else {
+            bail!("preset `{name}` needs exactly one size");
+        };
+        let fit = Fit::from_name(fit).with_context(|| format!("unknown fit `{fit}`"))?;
+        let format = ThumbnailFormat::from_extension(format)
+            .with_context(|| format!("unsupported format `{format}`"))?;
+        let quality: u8 = quality
+            .parse()
+            .with_context(|| format!("bad quality in `{entry}`"))?;
+
+        parsed.push(Preset {
+            name: name.to_string(),
+            options: ThumbnailOptions {
+                width,
+                height,
+                fit,
+                format,
+                quality: format.normalize_quality(Some(quality)),
+            },
+        });
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_database_file_of_sqlite_urls() {
+        assert_eq!(
+            sqlite_path("sqlite:images.db?mode=rwc").unwrap(),
+            PathBuf::from("images.db")
+        );
+        assert_eq!(
+            sqlite_path("sqlite:///var/lib/images.db").unwrap(),
+            PathBuf::from("/var/lib/images.db")
+        );
+        assert!(sqlite_path("sqlite::memory:").is_err());
+        assert!(sqlite_path("postgres://localhost/images").is_err());
+    }
+
+    #[test]
+    fn parses_size_list() {
+        assert_eq!(
+            parse_sizes(" 100x100, 320x240 ,").unwrap(),
+            vec![(100, 100), (320, 240)]
+        );
+    }
+
+    #[test]
+    fn default_presets_include_thumbnail() {
+        let config = Config::default();
+
+        assert_eq!(config.default_preset(), ThumbnailOptions::default());
+        assert!(config.preset("avatar").is_some());
+    }
+
+    #[test]
+    fn parses_presets() {
+        let presets =
+            parse_presets("card:320x240:cover:jpg:83; avatar:64x64:contain:png:10").unwrap();
+
+        assert_eq!(presets[0].definition(), "320x240:cover:jpg:85");
+        assert_eq!(presets[1].definition(), "64x64:contain:png:100");
+    }
+
+    #[test]
+    fn rejects_malformed_presets() {
+        assert!(parse_presets("card:320x240:cover:jpg").is_err());
+        assert!(parse_presets("../x:320x240:cover:jpg:80").is_err());
+        assert!(parse_presets("card:320x240:stretch:jpg:80").is_err());
+        assert!(parse_presets("a:1x1:fill:jpg:80;a:2x2:fill:jpg:80").is_err());
+    }
+
+    #[test]
+    fn detects_changed_presets() {
+        let config = Config::default();
+        let mut stored: HashMap<String, String> = config
+            .presets
+            .iter()
+            .map(|preset| (preset.name.clone(), preset.definition()))
+            .collect();
+        assert!(config.changed_presets(&stored).is_empty());
+
+        stored.insert("card".to_string(), "320x240:contain:jpg:85".to_string());
+        stored.remove("hero");
+        let changed: Vec<String> = config
+            .changed_presets(&stored)
+            .into_iter()
+            .map(|preset| preset.name)
+            .collect();
+        assert_eq!(changed, vec!["card", "hero"]);
+    }
+
+    #[test]
+    fn part_sizes_are_within_s3_limits() {
+        assert_eq!(parse_part_size("5242880").unwrap(), MIN_PART_SIZE);
+        assert!(parse_part_size("1048576").is_err());
+        assert!(parse_part_size("6000000000").is_err());
+        assert!(parse_part_size("8M").is_err());
+
+        assert!(check_part_count(MIN_PART_SIZE, DEFAULT_MAX_UPLOAD_SIZE).is_ok());
+        assert!(check_part_count(MIN_PART_SIZE, MIN_PART_SIZE * MAX_PARTS).is_ok());
+        assert!(check_part_count(MIN_PART_SIZE, MIN_PART_SIZE * MAX_PARTS + 1).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_sizes() {
+        assert!(parse_sizes("100").is_err());
+        assert!(parse_sizes("0x100").is_err());
+        assert!(parse_sizes("axb").is_err());
+    }
+}
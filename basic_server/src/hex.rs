@@ -0,0 +1,6 @@
+// Lowercase hex encoding of digests, for checksums and request signatures.
+
+/// Encodes `bytes` as lowercase hex, two digits per byte.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
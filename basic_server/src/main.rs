@@ -1,46 +1,239 @@
+mod config;
+mod hex;
 mod repository;
 mod routes;
 mod service;
+mod storage;
+#[cfg(test)]
+mod test_support;
 
+use std::str::FromStr;
 use std::sync::Arc;
 
+use anyhow::Context;
 use axum::{response::Html, routing::get, Router};
-use dotenv;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
 use sqlx::{Pool, Sqlite};
+use tokio::sync::Notify;
 
-use crate::routes::image_routes::{fill_missing_thumbnails, image_routes};
+use crate::config::{Config, ConfigProvider, StorageBackend};
+use crate::repository::settings_repository::SettingsRepository;
+use crate::routes::admin_routes::admin_routes;
+use crate::routes::contact_sheet_routes::contact_sheet_routes;
+use crate::routes::image_routes::{image_routes, sync_thumbnail_presets};
+use crate::routes::job_routes::job_routes;
+use crate::routes::tile_routes::tile_routes;
+use crate::service::backfill::{reconcile, spawn_backfill, RECONCILE_GRACE};
+use crate::service::gc::collect_garbage;
+use crate::service::image_executor::{ImageExecutor, ImageExecutorProvider};
+use crate::service::job_queue::spawn_job_workers;
+use crate::storage::local::{migrate_to_sharded, Layout, LocalBlobStore, LAYOUT_SETTING};
+use crate::storage::s3::S3BlobStore;
+use crate::storage::sqlite::SqliteBlobStore;
+use crate::storage::{BlobStore, BlobStoreProvider};
 
 #[derive(Clone)]
 struct AppState {
     db_pool: Pool<Sqlite>,
+    config: Config,
+    blob_store: Arc<dyn BlobStore>,
+    /// Wakes idle job workers when a job is enqueued.
+    job_notify: Arc<Notify>,
+    /// Bounds CPU-heavy image work; shared by handlers and job workers.
+    image_executor: ImageExecutor,
 }
 
 impl AppState {
-    fn new(db_pool: Pool<Sqlite>) -> Arc<Self> {
-        Arc::new(Self { db_pool })
+    fn new(db_pool: Pool<Sqlite>, config: Config, blob_store: Arc<dyn BlobStore>) -> Arc<Self> {
+        let image_executor = ImageExecutor::new(config.image_workers);
+        Arc::new(Self {
+            db_pool,
+            config,
+            blob_store,
+            job_notify: Arc::new(Notify::new()),
+            image_executor,
+        })
+    }
+}
+
+impl ConfigProvider for AppState {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
+impl BlobStoreProvider for AppState {
+    fn blob_store(&self) -> &dyn BlobStore {
+        self.blob_store.as_ref()
+    }
+}
+
+impl ImageExecutorProvider for AppState {
+    fn image_executor(&self) -> &ImageExecutor {
+        &self.image_executor
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv()?;
-    let db_url = std::env::var("DATABASE_URL")?;
-    let pool = sqlx::SqlitePool::connect(&db_url).await?;
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    let config = Config::from_env()?;
 
-    let app_state = AppState::new(pool);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        return run_command(command, &args[1..], &config).await;
+    }
+
+    let pool = connect_database().await?;
+    let blob_store = open_blob_store(&config, &pool).await?;
+    let app_state = AppState::new(pool, config, blob_store);
     let app = Router::new()
         .route("/", get(index_page))
-        .merge(image_routes(app_state.clone()));
+        .merge(image_routes(app_state.clone()))
+        .merge(tile_routes(app_state.clone()))
+        .merge(job_routes(app_state.clone()))
+        .merge(contact_sheet_routes(app_state.clone()))
+        .merge(admin_routes(app_state.clone()));
 
-    fill_missing_thumbnails(app_state.clone()).await?;
+    spawn_job_workers(app_state.clone());
+    sync_thumbnail_presets(app_state.clone()).await?;
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    spawn_backfill(app_state.clone());
     axum::serve(listener, app).await.unwrap();
 
     Ok(())
 }
 
+// In WAL mode readers never block writers, so a slow client streaming a blob
+// out of the `blobs` table does not hold up uploads and jobs.
+async fn connect_database() -> anyhow::Result<Pool<Sqlite>> {
+    let db_url = std::env::var("DATABASE_URL")?;
+    let options = SqliteConnectOptions::from_str(&db_url)?.journal_mode(SqliteJournalMode::Wal);
+    let pool = sqlx::SqlitePool::connect_with(options).await?;
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    Ok(pool)
+}
+
+async fn open_blob_store(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+) -> anyhow::Result<Arc<dyn BlobStore>> {
+    let blob_store: Arc<dyn BlobStore> = match &config.storage {
+        StorageBackend::Local(root) => match pool.setting(LAYOUT_SETTING).await? {
+            Some(name) => {
+                let layout = Layout::from_name(&name)
+                    .with_context(|| format!("unknown storage layout `{name}`"))?;
+                Arc::new(LocalBlobStore::with_layout(root, layout))
+            }
+            // Never migrated: the flat layout of older installations.
+            None => Arc::new(LocalBlobStore::new(root)),
+        },
+        StorageBackend::S3(s3) => Arc::new(S3BlobStore::new(s3.clone())?),
+        StorageBackend::Sqlite(path) => Arc::new(SqliteBlobStore::new(path)),
+    };
+    Ok(blob_store)
+}
+
+// One-shot maintenance commands, e.g. `basic_server sign-url /thumbnails/7 3600`.
+async fn run_command(command: &str, args: &[String], config: &Config) -> anyhow::Result<()> {
+    match command {
+        "sign-url" => {
+            let Some(path_and_query) = args.first() else {
+                anyhow::bail!("usage: basic_server sign-url <path?query> [ttl-seconds]");
+            };
+            let Some(signer) = &config.url_signer else {
+                anyhow::bail!("URL_SIGNING_KEY is not set");
+            };
+            let signed = match args.get(1) {
+                Some(ttl) => signer.sign_for(path_and_query, ttl.parse()?),
+                None => signer.sign(path_and_query, None),
+            };
+            println!("{signed}");
+            Ok(())
+        }
+        // Moves a flat local store into the sharded layout. Safe to run next to
+        // a live server and to re-run after an interruption.
+        "migrate-storage" => {
+            let dry_run = match args.first().map(String::as_str) {
+                None => false,
+                Some("--dry-run") => true,
+                Some(_) => anyhow::bail!("usage: basic_server migrate-storage [--dry-run]"),
+            };
+            let StorageBackend::Local(root) = &config.storage else {
+                anyhow::bail!("migrate-storage only applies to STORAGE_BACKEND=local");
+            };
+            let pool = connect_database().await?;
+            if !dry_run {
+                // Recorded first, so blobs written from now on already go to shards.
+                pool.save_setting(LAYOUT_SETTING, Layout::Sharded.as_str())
+                    .await?;
+            }
+            let root = root.clone();
+            let report =
+                tokio::task::spawn_blocking(move || migrate_to_sharded(&root, dry_run)).await??;
+            let verb = if dry_run { "would move" } else { "moved" };
+            println!(
+                "migrate-storage: {verb} {} files, {} superseded by sharded copies",
+                report.moved, report.superseded
+            );
+            if !dry_run {
+                println!("Restart running servers so they write to the sharded layout.");
+            }
+            Ok(())
+        }
+        // Reconciles the database with the blob store once, like the server
+        // does periodically; `--dry-run` only reports what it would change.
+        "reconcile" => {
+            let dry_run = match args.first().map(String::as_str) {
+                None => false,
+                Some("--dry-run") => true,
+                Some(_) => anyhow::bail!("usage: basic_server reconcile [--dry-run]"),
+            };
+            let pool = connect_database().await?;
+            let blob_store = open_blob_store(config, &pool).await?;
+            let state = AppState::new(pool, config.clone(), blob_store);
+            let report = reconcile(&*state, RECONCILE_GRACE, dry_run).await?;
+            if report.is_empty() {
+                println!("reconcile: nothing to do");
+            }
+            for line in report.lines(dry_run) {
+                println!("reconcile: {line}");
+            }
+            Ok(())
+        }
+        // Deletes orphaned blobs and stale variants older than the grace
+        // period (`GC_GRACE` unless `--grace` is given, in seconds).
+        "gc" => {
+            let usage = "usage: basic_server gc [--dry-run] [--grace <seconds>]";
+            let mut dry_run = false;
+            let mut grace = config.gc_grace;
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--dry-run" => dry_run = true,
+                    "--grace" => {
+                        let seconds = args.next().context(usage)?;
+                        grace = std::time::Duration::from_secs(
+                            seconds.parse().context("invalid --grace")?,
+                        );
+                    }
+                    _ => anyhow::bail!(usage),
+                }
+            }
+            let pool = connect_database().await?;
+            let blob_store = open_blob_store(config, &pool).await?;
+            let state = AppState::new(pool, config.clone(), blob_store);
+            let report = collect_garbage(&*state, grace, dry_run).await?;
+            for line in report.lines() {
+                println!("gc: {line}");
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!("unknown command `{command}`"),
+    }
+}
+
 async fn index_page() -> Html<String> {
     let path = std::path::Path::new("./src/templates/index.html");
     let content = tokio::fs::read_to_string(&path).await.unwrap();
@@ -1,3 +1,4 @@
+use crate::repository::job_repository::unix_now;
 use crate::AppState;
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -5,28 +6,117 @@ use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqliteArguments;
 use sqlx::{Arguments, Row};
 
+/// Where an image stands with its thumbnails.
+#[derive(sqlx::Type, Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailStatus {
+    /// Not generated yet, or being (re)generated.
+    #[default]
+    Pending,
+    Ready,
+    /// Generation was given up; see `thumbnail_error`.
+    Failed,
+}
+
 #[derive(sqlx::FromRow, Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct Image {
     pub(crate) id: i64,
     pub tags: String,
-    pub thumbnail: bool,
+    pub thumbnail_status: ThumbnailStatus,
+    /// Why the last generation failed.
+    pub thumbnail_error: Option<String>,
+    /// `thumbnail::GENERATOR_VERSION` the thumbnails were generated with.
+    pub thumbnail_version: Option<u32>,
+    pub mime_type: String,
+    /// Name of the file as uploaded, without any directory part.
+    pub file_name: Option<String>,
+    /// Upload time in Unix seconds; unknown for older images.
+    pub created_at: Option<i64>,
+    /// SHA-256 of the original as lowercase hex; unknown for older images.
+    pub sha256: Option<String>,
+    /// Size of the original in bytes; unknown for older images.
+    pub size: Option<i64>,
 }
 
 impl Image {
-    pub fn new(id: i64, tags: String, thumbnail: bool) -> Self {
+    #[cfg(test)]
+    pub fn new(id: i64, tags: String, thumbnail_status: ThumbnailStatus) -> Self {
         Self {
             id,
             tags,
-            thumbnail,
+            thumbnail_status,
+            thumbnail_error: None,
+            thumbnail_version: None,
+            mime_type: "image/jpeg".to_string(),
+            file_name: None,
+            created_at: None,
+            sha256: None,
+            size: None,
+        }
+    }
+
+    /// Whether thumbnails still have to be generated: they never were, or
+    /// an older generator made them. Failed images are left alone.
+    pub fn needs_thumbnails(&self) -> bool {
+        match self.thumbnail_status {
+            ThumbnailStatus::Pending => true,
+            ThumbnailStatus::Ready => self.thumbnail_version < Some(thumbnail::GENERATOR_VERSION),
+            ThumbnailStatus::Failed => false,
+        }
+    }
+
+    pub fn thumbnails_ready(&mut self, version: u32) {
+        self.thumbnail_status = ThumbnailStatus::Ready;
+        self.thumbnail_error = None;
+        self.thumbnail_version = Some(version);
+    }
+
+    /// Marks the thumbnails for regeneration, e.g. after the original changed.
+    pub fn thumbnails_pending(&mut self) {
+        self.thumbnail_status = ThumbnailStatus::Pending;
+        self.thumbnail_error = None;
+        self.thumbnail_version = None;
+    }
+
+    pub fn thumbnails_failed(&mut self, error: String) {
+        self.thumbnail_status = ThumbnailStatus::Failed;
+        self.thumbnail_error = Some(error);
+    }
+
+    /// File name to present to clients: the uploaded name, or the id with
+    /// the extension of the stored format.
+    pub fn download_name(&self) -> String {
+        match &self.file_name {
+            Some(file_name) if !file_name.is_empty() => file_name.clone(),
+            _ => format!("{}.{}", self.id, self.extension()),
         }
     }
+
+    /// Usual file extension of the stored format.
+    pub fn extension(&self) -> &'static str {
+        image::ImageFormat::from_mime_type(&self.mime_type)
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("bin")
+    }
+}
+
+/// An upload to record; the id is assigned by the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewImage {
+    pub tags: String,
+    pub mime_type: String,
+    pub file_name: Option<String>,
+    pub sha256: Option<String>,
+    pub size: Option<i64>,
 }
 
 #[derive(sqlx::FromRow, Debug, PartialEq, Eq)]
 pub struct ImageFilter {
     pub id: Option<i64>,
     pub tags: Option<String>,
-    pub thumbnail: Option<bool>,
+    pub thumbnail_status: Option<ThumbnailStatus>,
+    pub sha256: Option<String>,
 }
 
 #[derive(Debug)]
@@ -38,14 +128,12 @@ pub enum ImageResult {
 #[async_trait]
 pub trait ImageRepository: Send + Sync + 'static {
     async fn count(&self) -> String;
-    async fn insert(&self, tags: &str) -> Result<i64>;
+    async fn insert(&self, image: &NewImage) -> Result<i64>;
     async fn delete(&self, id: i64) -> Result<()>;
     async fn update(&self, image: Image) -> Result<()>;
     async fn filter(&self, filter: ImageFilter) -> Result<ImageResult>;
 }
 
-type Thumbnail = fn(i64) -> Result<()>;
-
 #[async_trait]
 impl ImageRepository for AppState {
     async fn count(&self) -> String {
@@ -57,11 +145,19 @@ impl ImageRepository for AppState {
         format!("{count} images in the database")
     }
 
-    async fn insert(&self, tags: &str) -> Result<i64> {
-        let row = sqlx::query("INSERT INTO images (tags) VALUES (?) RETURNING id")
-            .bind(tags)
-            .fetch_one(&self.db_pool)
-            .await?;
+    async fn insert(&self, image: &NewImage) -> Result<i64> {
+        let row = sqlx::query(
+            "INSERT INTO images (tags, mime_type, file_name, created_at, sha256, size) \
+             VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(&image.tags)
+        .bind(&image.mime_type)
+        .bind(&image.file_name)
+        .bind(unix_now())
+        .bind(&image.sha256)
+        .bind(image.size)
+        .fetch_one(&self.db_pool)
+        .await?;
         Ok(row.get(0))
     }
 
@@ -75,12 +171,21 @@ impl ImageRepository for AppState {
 
     async fn update(&self, image: Image) -> Result<()> {
         println!("update");
-        sqlx::query("UPDATE images SET thumbnail = ?, tags = ? WHERE id = ?")
-            .bind(image.thumbnail)
-            .bind(image.tags.clone())
-            .bind(image.id)
-            .execute(&self.db_pool)
-            .await?;
+        sqlx::query(
+            "UPDATE images SET thumbnail_status = ?, thumbnail_error = ?, thumbnail_version = ?, \
+             tags = ?, mime_type = ?, file_name = ?, sha256 = ?, size = ? WHERE id = ?",
+        )
+        .bind(image.thumbnail_status)
+        .bind(image.thumbnail_error.clone())
+        .bind(image.thumbnail_version)
+        .bind(image.tags.clone())
+        .bind(image.mime_type.clone())
+        .bind(image.file_name.clone())
+        .bind(image.sha256.clone())
+        .bind(image.size)
+        .bind(image.id)
+        .execute(&self.db_pool)
+        .await?;
 
         Ok(())
     }
@@ -94,9 +199,14 @@ impl ImageRepository for AppState {
             args.add(format!("%{}%", tags));
         }
 
-        if let Some(thumbnail) = filters.thumbnail {
-            query += " AND thumbnail = ?";
-            args.add(thumbnail as i64);
+        if let Some(status) = filters.thumbnail_status {
+            query += " AND thumbnail_status = ?";
+            args.add(status);
+        }
+
+        if let Some(ref sha256) = filters.sha256 {
+            query += " AND sha256 = ?";
+            args.add(sha256.clone());
         }
 
         if let Some(id) = filters.id {
@@ -119,9 +229,41 @@ impl ImageRepository for AppState {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn download_name_keeps_original_extension() {
+        let mut image = Image::new(7, String::new(), ThumbnailStatus::Pending);
+        image.mime_type = "image/png".to_string();
+        assert_eq!(image.download_name(), "7.png");
+
+        image.file_name = Some("holiday.PNG".to_string());
+        assert_eq!(image.download_name(), "holiday.PNG");
+    }
+
+    #[test]
+    fn outdated_and_pending_images_need_thumbnails() {
+        let mut image = Image::new(7, String::new(), ThumbnailStatus::Pending);
+        assert!(image.needs_thumbnails());
+
+        image.thumbnails_ready(thumbnail::GENERATOR_VERSION);
+        assert!(!image.needs_thumbnails());
+        image.thumbnail_version = Some(thumbnail::GENERATOR_VERSION - 1);
+        assert!(image.needs_thumbnails());
+        image.thumbnail_version = None;
+        assert!(image.needs_thumbnails());
+
+        image.thumbnails_failed("unsupported format".to_string());
+        assert!(!image.needs_thumbnails());
+        assert_eq!(
+            serde_json::to_value(&image).unwrap()["thumbnail_status"],
+            "failed"
+        );
+    }
 }
@@ -0,0 +1,389 @@
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use sqlx::sqlite::SqliteRow;
+use sqlx::Row;
+
+use crate::service::image_executor::Priority;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        Ok(match name {
+            "queued" => JobState::Queued,
+            "running" => JobState::Running,
+            "done" => JobState::Done,
+            "failed" => JobState::Failed,
+            _ => bail!("unknown job state `{name}`"),
+        })
+    }
+}
+
+/// A thumbnail generation job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: i64,
+    pub image_id: i64,
+    /// Presets to render; `None` renders all configured presets.
+    pub presets: Option<Vec<String>>,
+    pub state: JobState,
+    pub priority: Priority,
+    /// Runs started so far, including the current one.
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix seconds.
+    pub created_at: i64,
+    /// Start of the latest run, in Unix seconds.
+    pub started_at: Option<i64>,
+    /// Last state change, in Unix seconds.
+    pub updated_at: i64,
+}
+
+impl TryFrom<SqliteRow> for Job {
+    type Error = anyhow::Error;
+
+    fn try_from(row: SqliteRow) -> Result<Self> {
+        let presets: Option<String> = row.try_get("presets")?;
+        Ok(Self {
+            id: row.try_get("id")?,
+            image_id: row.try_get("image_id")?,
+            presets: presets.map(|names| names.split(',').map(str::to_string).collect()),
+            state: JobState::from_name(row.try_get("state")?)?,
+            priority: Priority::from_rank(row.try_get("priority")?),
+            attempts: row.try_get("attempts")?,
+            last_error: row.try_get("last_error")?,
+            created_at: row.try_get("created_at")?,
+            started_at: row.try_get("started_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+/// Error recorded for runs whose worker stopped, e.g. because the process
+/// crashed or ran out of memory decoding the image.
+pub const WORKER_STOPPED: &str = "worker stopped while running";
+
+/// `running` jobs whose worker is gone, as handled by
+/// [`JobRepository::requeue_stale`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StaleJobs {
+    /// How many were put back in the queue.
+    pub requeued: u64,
+    /// Jobs that had used up their attempts and were marked `failed`.
+    pub failed: Vec<Job>,
+}
+
+/// Work to enqueue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewJob {
+    pub image_id: i64,
+    pub presets: Option<Vec<String>>,
+    /// Interactive jobs are claimed before any background job.
+    pub priority: Priority,
+}
+
+#[async_trait]
+pub trait JobRepository: Send + Sync + 'static {
+    /// Queues a job and wakes an idle worker.
+    async fn enqueue(&self, job: &NewJob) -> Result<i64>;
+
+    async fn job(&self, id: i64) -> Result<Option<Job>>;
+
+    /// The most recently created job of an image.
+    async fn latest_job_for_image(&self, image_id: i64) -> Result<Option<Job>>;
+
+    /// Atomically moves the due job with the highest priority (the oldest
+    /// among equals) to `running` and returns it.
+    async fn claim_next(&self) -> Result<Option<Job>>;
+
+    async fn complete(&self, id: i64) -> Result<()>;
+
+    /// Records a failed run. With `retry_at` (Unix seconds) the job is
+    /// queued again, otherwise it is marked `failed` for good.
+    async fn fail(&self, id: i64, error: &str, retry_at: Option<i64>) -> Result<()>;
+
+    /// Puts jobs that have been `running` since before `started_before` back
+    /// in the queue; their worker died with the process. Jobs that already
+    /// had `max_attempts` runs are marked `failed` instead, so a job that
+    /// kills the process is not retried forever.
+    async fn requeue_stale(&self, started_before: i64, max_attempts: u32) -> Result<StaleJobs>;
+
+    /// Images that have a queued or running job.
+    async fn images_with_pending_jobs(&self) -> Result<Vec<i64>>;
+
+    /// Resolves once a job may have been enqueued.
+    async fn job_enqueued(&self);
+}
+
+#[async_trait]
+impl JobRepository for AppState {
+    async fn enqueue(&self, job: &NewJob) -> Result<i64> {
+        let now = unix_now();
+        let row = sqlx::query(
+            "INSERT INTO jobs (image_id, presets, priority, run_after, created_at, updated_at) \
+             VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(job.image_id)
+        .bind(job.presets.as_ref().map(|names| names.join(",")))
+        .bind(job.priority.rank())
+        .bind(now)
+        .bind(now)
+        .bind(now)
+        .fetch_one(&self.db_pool)
+        .await?;
+        self.job_notify.notify_one();
+        Ok(row.get(0))
+    }
+
+    async fn job(&self, id: i64) -> Result<Option<Job>> {
+        let row = sqlx::query("SELECT * FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        row.map(Job::try_from).transpose()
+    }
+
+    async fn latest_job_for_image(&self, image_id: i64) -> Result<Option<Job>> {
+        let row = sqlx::query("SELECT * FROM jobs WHERE image_id = ? ORDER BY id DESC LIMIT 1")
+            .bind(image_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        row.map(Job::try_from).transpose()
+    }
+
+    async fn claim_next(&self) -> Result<Option<Job>> {
+        let now = unix_now();
+        let row = sqlx::query(
+            "UPDATE jobs SET state = 'running', attempts = attempts + 1, started_at = ?, \
+             updated_at = ? \
+             WHERE id = (SELECT id FROM jobs WHERE state = 'queued' AND run_after <= ? \
+                         ORDER BY priority DESC, run_after, id LIMIT 1) \
+             RETURNING *",
+        )
+        .bind(now)
+        .bind(now)
+        .bind(now)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        row.map(Job::try_from).transpose()
+    }
+
+    async fn complete(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE jobs SET state = 'done', last_error = NULL, updated_at = ? WHERE id = ?",
+        )
+        .bind(unix_now())
+        .bind(id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn fail(&self, id: i64, error: &str, retry_at: Option<i64>) -> Result<()> {
+        let state = match retry_at {
+            Some(_) => JobState::Queued,
+            None => JobState::Failed,
+        };
+        let now = unix_now();
+        sqlx::query(
+            "UPDATE jobs SET state = ?, last_error = ?, run_after = ?, updated_at = ? WHERE id = ?",
+        )
+        .bind(state.as_str())
+        .bind(error)
+        .bind(retry_at.unwrap_or(now))
+        .bind(now)
+        .bind(id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn requeue_stale(&self, started_before: i64, max_attempts: u32) -> Result<StaleJobs> {
+        let now = unix_now();
+        let mut transaction = self.db_pool.begin().await?;
+        let failed = sqlx::query(
+            "UPDATE jobs SET state = 'failed', last_error = ?, updated_at = ? \
+             WHERE state = 'running' AND started_at < ? AND attempts >= ? RETURNING *",
+        )
+        .bind(WORKER_STOPPED)
+        .bind(now)
+        .bind(started_before)
+        .bind(max_attempts)
+        .fetch_all(&mut *transaction)
+        .await?;
+        let requeued = sqlx::query(
+            "UPDATE jobs SET state = 'queued', last_error = ?, run_after = ?, updated_at = ? \
+             WHERE state = 'running' AND started_at < ?",
+        )
+        .bind(WORKER_STOPPED)
+        .bind(now)
+        .bind(now)
+        .bind(started_before)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(StaleJobs {
+            requeued: requeued.rows_affected(),
+            failed: failed
+                .into_iter()
+                .map(Job::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    async fn images_with_pending_jobs(&self) -> Result<Vec<i64>> {
+        let rows =
+            sqlx::query("SELECT DISTINCT image_id FROM jobs WHERE state IN ('queued', 'running')")
+                .fetch_all(&self.db_pool)
+                .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn job_enqueued(&self) {
+        self.job_notify.notified().await
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::test_support::app_state;
+
+    fn job(image_id: i64) -> NewJob {
+        NewJob {
+            image_id,
+            presets: None,
+            priority: Priority::Background,
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_are_claimed_once_in_order() {
+        let state = app_state(Config::default()).await;
+        let first = state.enqueue(&job(7)).await.unwrap();
+        state
+            .enqueue(&NewJob {
+                image_id: 8,
+                presets: Some(vec!["card".to_string(), "hero".to_string()]),
+                priority: Priority::Background,
+            })
+            .await
+            .unwrap();
+
+        let claimed = state.claim_next().await.unwrap().unwrap();
+        assert_eq!(claimed.id, first);
+        assert_eq!(claimed.state, JobState::Running);
+        assert_eq!(claimed.attempts, 1);
+
+        let second = state.claim_next().await.unwrap().unwrap();
+        assert_eq!(second.presets.as_deref().unwrap(), ["card", "hero"]);
+        assert!(state.claim_next().await.unwrap().is_none());
+        assert_eq!(state.images_with_pending_jobs().await.unwrap().len(), 2);
+
+        state.complete(first).await.unwrap();
+        assert_eq!(state.images_with_pending_jobs().await.unwrap(), vec![8]);
+        let done = state.job(first).await.unwrap().unwrap();
+        assert_eq!(done.state, JobState::Done);
+        assert!(done.started_at.is_some());
+        assert_eq!(
+            state.latest_job_for_image(8).await.unwrap().unwrap().id,
+            second.id
+        );
+        assert!(state.latest_job_for_image(9).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn interactive_jobs_are_claimed_first() {
+        let state = app_state(Config::default()).await;
+        state.enqueue(&job(7)).await.unwrap();
+        let upload = state
+            .enqueue(&NewJob {
+                priority: Priority::Interactive,
+                ..job(8)
+            })
+            .await
+            .unwrap();
+
+        let claimed = state.claim_next().await.unwrap().unwrap();
+        assert_eq!(claimed.id, upload);
+        assert_eq!(claimed.priority, Priority::Interactive);
+        assert_eq!(state.claim_next().await.unwrap().unwrap().image_id, 7);
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_wait_for_their_retry() {
+        let state = app_state(Config::default()).await;
+        let id = state.enqueue(&job(7)).await.unwrap();
+        state.claim_next().await.unwrap();
+
+        state
+            .fail(id, "decoder exploded", Some(unix_now() + 60))
+            .await
+            .unwrap();
+        assert!(state.claim_next().await.unwrap().is_none());
+
+        state.fail(id, "decoder exploded", Some(0)).await.unwrap();
+        let retried = state.claim_next().await.unwrap().unwrap();
+        assert_eq!(retried.attempts, 2);
+        assert_eq!(retried.last_error.as_deref(), Some("decoder exploded"));
+
+        state.fail(id, "decoder exploded", None).await.unwrap();
+        assert!(state.images_with_pending_jobs().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_running_jobs_are_requeued() {
+        let state = app_state(Config::default()).await;
+        state.enqueue(&job(7)).await.unwrap();
+        state.claim_next().await.unwrap();
+
+        let stale = state.requeue_stale(unix_now() - 60, 5).await.unwrap();
+        assert_eq!(stale, StaleJobs::default());
+        let stale = state.requeue_stale(unix_now() + 1, 5).await.unwrap();
+        assert_eq!(stale.requeued, 1);
+        assert_eq!(state.claim_next().await.unwrap().unwrap().attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn stale_jobs_out_of_attempts_fail() {
+        let state = app_state(Config::default()).await;
+        let id = state.enqueue(&job(7)).await.unwrap();
+        state.claim_next().await.unwrap();
+        state.fail(id, "decoder exploded", Some(0)).await.unwrap();
+        state.claim_next().await.unwrap();
+
+        let stale = state.requeue_stale(unix_now() + 1, 2).await.unwrap();
+        assert_eq!(stale.requeued, 0);
+        assert_eq!(stale.failed.len(), 1);
+        assert_eq!(stale.failed[0].state, JobState::Failed);
+        assert_eq!(stale.failed[0].last_error.as_deref(), Some(WORKER_STOPPED));
+        assert!(state.claim_next().await.unwrap().is_none());
+    }
+}
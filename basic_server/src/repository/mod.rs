@@ -1 +1,4 @@
 pub mod image_repository;
+pub mod job_repository;
+pub mod preset_repository;
+pub mod settings_repository;
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::Row;
+
+use crate::AppState;
+
+#[async_trait]
+pub trait PresetRepository: Send + Sync + 'static {
+    /// Preset name -> definition the stored thumbnails were generated with.
+    async fn preset_definitions(&self) -> Result<HashMap<String, String>>;
+    async fn save_preset_definition(&self, name: &str, definition: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl PresetRepository for AppState {
+    async fn preset_definitions(&self) -> Result<HashMap<String, String>> {
+        let rows = sqlx::query("SELECT name, definition FROM thumbnail_presets")
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("name"), row.get("definition")))
+            .collect())
+    }
+
+    async fn save_preset_definition(&self, name: &str, definition: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO thumbnail_presets (name, definition) VALUES (?, ?) \
+             ON CONFLICT(name) DO UPDATE SET definition = excluded.definition",
+        )
+        .bind(name)
+        .bind(definition)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+}
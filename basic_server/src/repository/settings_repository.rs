@@ -0,0 +1,36 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::{Pool, Row, Sqlite};
+
+/// Named values persisted in the `settings` table.
+///
+/// Implemented on the pool rather than `AppState`, as some settings are needed
+/// to build the state (e.g. the storage layout).
+#[async_trait]
+pub trait SettingsRepository: Send + Sync + 'static {
+    async fn setting(&self, name: &str) -> Result<Option<String>>;
+    async fn save_setting(&self, name: &str, value: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl SettingsRepository for Pool<Sqlite> {
+    async fn setting(&self, name: &str) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT value FROM settings WHERE name = ?")
+            .bind(name)
+            .fetch_optional(self)
+            .await?;
+        Ok(row.map(|row| row.get("value")))
+    }
+
+    async fn save_setting(&self, name: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO settings (name, value) VALUES (?, ?) \
+             ON CONFLICT(name) DO UPDATE SET value = excluded.value",
+        )
+        .bind(name)
+        .bind(value)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+// Maintenance endpoints for operators, behind `ADMIN_TOKEN`.
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use crate::config::ConfigProvider;
+use crate::repository::image_repository::ImageRepository;
+use crate::routes::api_error::api_error;
+use crate::service::gc::collect_garbage;
+use crate::storage::BlobStoreProvider;
+
+pub fn admin_routes<T: ImageRepository + ConfigProvider + BlobStoreProvider>(
+    state: Arc<T>,
+) -> Router {
+    Router::new()
+        .route("/admin/gc", post(run_gc::<T>))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_admin_token::<T>,
+        ))
+        .with_state(state)
+}
+
+/// Requires `Authorization: Bearer <ADMIN_TOKEN>`. Without a configured
+/// token the routes behind it do not exist.
+pub async fn require_admin_token<T: ConfigProvider>(
+    State(repo): State<Arc<T>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = &repo.config().admin_token else {
+        return api_error(StatusCode::NOT_FOUND, "not found");
+    };
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if token.matches(presented) => next.run(request).await,
+        _ => {
+            let mut response = api_error(StatusCode::UNAUTHORIZED, "admin token required");
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+            response
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GcQuery {
+    /// Only report what would be deleted.
+    #[serde(default)]
+    dry_run: bool,
+    /// Overrides `GC_GRACE`, in seconds.
+    grace: Option<u64>,
+}
+
+async fn run_gc<T: ImageRepository + ConfigProvider + BlobStoreProvider>(
+    State(repo): State<Arc<T>>,
+    Query(query): Query<GcQuery>,
+) -> Response {
+    let grace = query
+        .grace
+        .map(Duration::from_secs)
+        .unwrap_or(repo.config().gc_grace);
+    match collect_garbage(&*repo, grace, query.dry_run).await {
+        Ok(report) => {
+            if !report.dry_run {
+                report
+                    .lines()
+                    .iter()
+                    .for_each(|line| println!("GC: {line}"));
+            }
+            Json(report).into_response()
+        }
+        Err(e) => {
+            eprintln!("Garbage collection failed: {e:#}");
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "garbage collection failed",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    use crate::config::{AdminToken, Config};
+
+    struct TestState(Config);
+
+    impl ConfigProvider for TestState {
+        fn config(&self) -> &Config {
+            &self.0
+        }
+    }
+
+    fn router(admin_token: Option<&str>) -> Router {
+        let state = Arc::new(TestState(Config {
+            admin_token: admin_token.map(AdminToken::new),
+            ..Config::default()
+        }));
+        Router::new()
+            .route("/admin/gc", post(|| async { "collected" }))
+            .route_layer(middleware::from_fn_with_state(
+                state,
+                require_admin_token::<TestState>,
+            ))
+    }
+
+    async fn status(router: Router, authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().method("POST").uri("/admin/gc");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_hidden_without_a_token() {
+        let status = status(router(None), Some("Bearer anything")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_require_the_token() {
+        let router = router(Some("s3cret"));
+        assert_eq!(status(router.clone(), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(router.clone(), Some("Bearer guess")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(router, Some("Bearer s3cret")).await, StatusCode::OK);
+    }
+}
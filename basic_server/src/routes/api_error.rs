@@ -0,0 +1,47 @@
+// JSON error bodies shared by the API routes.
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+/// A JSON error body such as `{"error": "job not found"}`. Validation
+/// failures also list the offending fields.
+#[derive(Debug, Serialize)]
+pub(crate) struct ApiError {
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// A problem with one field of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+pub(crate) fn api_error(status: StatusCode, message: impl Into<String>) -> Response {
+    let body = ApiError {
+        error: message.into(),
+        fields: Vec::new(),
+    };
+    (status, Json(body)).into_response()
+}
+
+/// `422 Unprocessable Entity` listing every invalid field.
+pub(crate) fn validation_failed(fields: Vec<FieldError>) -> Response {
+    let body = ApiError {
+        error: "validation failed".to_string(),
+        fields,
+    };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+}
@@ -0,0 +1,185 @@
+// `Range` request handling (RFC 9110 section 14) for served blobs.
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+use axum::body::{Body, Bytes};
+use futures::stream::{self, StreamExt};
+
+use crate::storage::{BlobStore, BlobStream};
+
+// More ranges than this are answered with the whole file, which RFC 9110
+// allows; it keeps clients from requesting thousands of tiny parts.
+const MAX_RANGES: usize = 16;
+
+/// How to answer a request given its `Range` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header: send the whole file.
+    Full,
+    /// Satisfiable byte ranges, end-exclusive, in request order.
+    Partial(Vec<Range<u64>>),
+    /// Syntactically valid, but no range overlaps the file: `416`.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header such as `bytes=0-499, -500` against a file of
+/// `len` bytes. Malformed headers and other units are ignored, as the RFC
+/// requires.
+pub fn parse_range(header: Option<&str>, len: u64) -> RangeRequest {
+    let Some(specs) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((start, end)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(suffix) => len.saturating_sub(suffix)..len,
+                Err(_) => return RangeRequest::Full,
+            },
+            (start, "") => match start.parse::<u64>() {
+                Ok(start) => start..len,
+                Err(_) => return RangeRequest::Full,
+            },
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(len),
+                _ => return RangeRequest::Full,
+            },
+        };
+        // Ranges starting past the end (and empty suffixes) are unsatisfiable.
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        // `bytes=` without any spec is malformed rather than unsatisfiable.
+        return if specs.trim().is_empty() {
+            RangeRequest::Full
+        } else {
+            RangeRequest::Unsatisfiable
+        };
+    }
+    RangeRequest::Partial(ranges)
+}
+
+/// `Content-Range` value of one part.
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{len}", range.start, range.end - 1)
+}
+
+/// Streams `range` of a blob.
+pub async fn range_body(store: &dyn BlobStore, key: &str, range: Range<u64>) -> Result<Body> {
+    let stream = store
+        .get_range(key, range)
+        .await?
+        .with_context(|| format!("blob `{key}` disappeared"))?;
+    Ok(Body::from_stream(stream))
+}
+
+/// Builds a `multipart/byteranges` body and returns it with its exact length.
+pub async fn multipart_body(
+    store: &dyn BlobStore,
+    key: &str,
+    ranges: &[Range<u64>],
+    content_type: &str,
+    len: u64,
+    boundary: &str,
+) -> Result<(Body, u64)> {
+    let mut parts: Vec<BlobStream> = Vec::new();
+    let mut body_len = 0;
+
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range, len)
+        );
+        body_len += part_header.len() as u64 + (range.end - range.start);
+        parts.push(stream::once(async move { Ok(Bytes::from(part_header)) }).boxed());
+
+        let part = store
+            .get_range(key, range.clone())
+            .await?
+            .with_context(|| format!("blob `{key}` disappeared"))?;
+        parts.push(part);
+    }
+
+    let trailer = format!("\r\n--{boundary}--\r\n");
+    body_len += trailer.len() as u64;
+    parts.push(stream::once(async move { Ok(Bytes::from(trailer)) }).boxed());
+
+    Ok((Body::from_stream(stream::iter(parts).flatten()), body_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::memory::MemoryBlobStore;
+
+    fn single(range: Range<u64>) -> RangeRequest {
+        RangeRequest::Partial(std::iter::once(range).collect())
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-499"), 1000), single(0..500));
+        assert_eq!(parse_range(Some("bytes=900-"), 1000), single(900..1000));
+        assert_eq!(parse_range(Some("bytes=-100"), 1000), single(900..1000));
+        assert_eq!(parse_range(Some("bytes=990-5000"), 1000), single(990..1000));
+    }
+
+    #[test]
+    fn parses_multiple_ranges() {
+        assert_eq!(
+            parse_range(Some("bytes=0-9, 20-29,-5"), 100),
+            RangeRequest::Partial(vec![0..10, 20..30, 95..100])
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        assert_eq!(parse_range(None, 100), RangeRequest::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=5-1"), 100), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=a-b"), 100), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes="), 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn detects_unsatisfiable_ranges() {
+        assert_eq!(
+            parse_range(Some("bytes=100-200"), 100),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-0"), 100),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[tokio::test]
+    async fn multipart_length_matches_body() {
+        let store = MemoryBlobStore::new();
+        store
+            .put("a.bin", Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+
+        let (body, len) = multipart_body(&store, "a.bin", &[0..2, 8..10], "image/jpeg", 10, "b")
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+
+        assert_eq!(bytes.len() as u64, len);
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n--b"));
+        assert!(text.ends_with("bytes 8-9/10\r\n\r\n89\r\n--b--\r\n"));
+    }
+}
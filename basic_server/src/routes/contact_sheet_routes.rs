@@ -0,0 +1,431 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use thumbnail::contact_sheet::{
+    compose, fit_to_cell, CaptionStyle, ContactSheetEntry, ContactSheetOptions, EntrySource, Rect,
+};
+use thumbnail::ThumbnailFormat;
+
+use crate::config::{Config, ConfigProvider};
+use crate::repository::image_repository::{Image, ImageFilter, ImageRepository, ImageResult};
+use crate::routes::admin_routes::require_admin_token;
+use crate::service::image_executor::{ImageExecutorProvider, Priority};
+use crate::storage::{original_key, BlobStoreProvider};
+
+// Upper bounds that keep a single request from composing an enormous canvas.
+const MAX_SHEET_IMAGES: usize = 100;
+const MAX_COLUMNS: u32 = 20;
+const MAX_CELL_SIZE: u32 = 512;
+/// Bounds the canvas as a whole, which the limits above alone do not.
+const MAX_SHEET_PIXELS: u64 = 4096 * 4096;
+const CAPTION_SIZE: f32 = 14.0;
+
+// Composing decodes many originals, so only operators may ask for sheets.
+pub fn contact_sheet_routes<T>(repository: Arc<T>) -> Router
+where
+    T: ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    Router::new()
+        .route("/contact-sheet", post(contact_sheet_handler))
+        .route_layer(middleware::from_fn_with_state(
+            repository.clone(),
+            require_admin_token::<T>,
+        ))
+        .with_state(repository)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ContactSheetRequest {
+    ids: Option<Vec<i64>>,
+    tags: Option<String>,
+    columns: Option<u32>,
+    cell_width: Option<u32>,
+    cell_height: Option<u32>,
+    padding: Option<u32>,
+    /// `#rrggbb` or `#rrggbbaa`.
+    background: Option<String>,
+    captions: Option<bool>,
+    /// `png`, `jpg`, `webp` or (with the `avif` feature) `avif`.
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContactSheetResponse {
+    /// The composed sheet as a `data:` URI.
+    image: String,
+    width: u32,
+    height: u32,
+    cells: Vec<ContactSheetCell>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ContactSheetCell {
+    id: i64,
+    cell: CellRect,
+    image: CellRect,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct CellRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for CellRect {
+    fn from(rect: Rect) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+async fn contact_sheet_handler<
+    T: ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+>(
+    State(repo): State<Arc<T>>,
+    Json(request): Json<ContactSheetRequest>,
+) -> Response {
+    let options = match sheet_options(&request, repo.config()).await {
+        Ok(options) => options,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+
+    let mut images = match select_images(repo.as_ref(), &request).await {
+        Ok(images) => images,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    images.truncate(MAX_SHEET_IMAGES);
+    if let Err(message) = check_sheet_size(images.len(), &options) {
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+
+    // Originals are decoded one at a time and only their scaled copies kept.
+    // Rows whose original is gone or unreadable are left out of the sheet.
+    let executor = repo.image_executor();
+    let (cell_width, cell_height) = (options.cell_width, options.cell_height);
+    let mut entries = Vec::with_capacity(images.len());
+    let mut ids = Vec::with_capacity(images.len());
+    for image in images {
+        let data = match repo.blob_store().get_bytes(&original_key(image.id)).await {
+            Ok(Some(data)) => data,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Failed to read image {}: {e}", image.id);
+                continue;
+            }
+        };
+        let scaled = executor
+            .run(Priority::Interactive, move || {
+                fit_to_cell(&data, cell_width, cell_height)
+            })
+            .await;
+        match scaled {
+            Ok(Ok(scaled)) => {
+                ids.push(image.id);
+                entries.push(ContactSheetEntry {
+                    source: EntrySource::Scaled(scaled),
+                    caption: Some(image.tags),
+                });
+            }
+            Ok(Err(e)) => eprintln!("Failed to decode image {}: {e}", image.id),
+            Err(e) => eprintln!("Contact sheet task failed: {e}"),
+        }
+    }
+    if entries.is_empty() {
+        return (StatusCode::NOT_FOUND, "no matching images").into_response();
+    }
+
+    let sheet = match executor
+        .run(Priority::Interactive, move || compose(&entries, &options))
+        .await
+    {
+        Ok(Ok(sheet)) => sheet,
+        Ok(Err(e)) => {
+            eprintln!("Failed to compose contact sheet: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to compose contact sheet",
+            )
+                .into_response();
+        }
+        Err(e) => {
+            eprintln!("Contact sheet task failed: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let cells = sheet
+        .cells
+        .iter()
+        .map(|cell| ContactSheetCell {
+            id: ids[cell.index],
+            cell: cell.cell.into(),
+            image: cell.image.into(),
+        })
+        .collect();
+
+    Json(ContactSheetResponse {
+        image: format!(
+            "data:{};base64,{}",
+            sheet.format.mime_type(),
+            BASE64.encode(&sheet.data)
+        ),
+        width: sheet.width,
+        height: sheet.height,
+        cells,
+    })
+    .into_response()
+}
+
+async fn select_images<T: ImageRepository>(
+    repo: &T,
+    request: &ContactSheetRequest,
+) -> Result<Vec<Image>, String> {
+    let filters: Vec<ImageFilter> = match (&request.ids, &request.tags) {
+        (Some(ids), _) if !ids.is_empty() => ids
+            .iter()
+            .take(MAX_SHEET_IMAGES)
+            .map(|id| ImageFilter {
+                id: Some(*id),
+                tags: None,
+                thumbnail_status: None,
+                sha256: None,
+            })
+            .collect(),
+        (_, Some(tags)) => vec![ImageFilter {
+            id: None,
+            tags: Some(tags.clone()),
+            thumbnail_status: None,
+            sha256: None,
+        }],
+        _ => return Err("either `ids` or `tags` is required".to_string()),
+    };
+
+    let mut images = Vec::new();
+    for filter in filters {
+        match repo.filter(filter).await {
+            Ok(ImageResult::Single(image)) => images.push(image),
+            Ok(ImageResult::Multiple(found)) => images.extend(found),
+            Err(e) => eprintln!("Failed to look up images for contact sheet: {e}"),
+        }
+    }
+    Ok(images)
+}
+
+// Rejects sheets whose canvas alone would take too much memory.
+fn check_sheet_size(count: usize, options: &ContactSheetOptions) -> Result<(), String> {
+    let (width, height) = options.sheet_size(count);
+    if u64::from(width) * u64::from(height) > MAX_SHEET_PIXELS {
+        return Err(format!(
+            "a sheet of {width}x{height} pixels exceeds the limit of {MAX_SHEET_PIXELS} pixels"
+        ));
+    }
+    Ok(())
+}
+
+async fn sheet_options(
+    request: &ContactSheetRequest,
+    config: &Config,
+) -> Result<ContactSheetOptions, String> {
+    let defaults = ContactSheetOptions::default();
+
+    let format = match request.format.as_deref() {
+        Some(extension) => ThumbnailFormat::from_extension(extension)
+            .ok_or_else(|| format!("unsupported format `{extension}`"))?,
+        None => defaults.format,
+    };
+    let background = match request.background.as_deref() {
+        Some(color) => parse_color(color).ok_or_else(|| format!("invalid background `{color}`"))?,
+        None => defaults.background,
+    };
+
+    // Captions need a font, `config.contact_sheet_font`.
+    let captions = if request.captions.unwrap_or(false) {
+        let font_path = config
+            .contact_sheet_font
+            .as_ref()
+            .ok_or_else(|| "captions are not configured on this server".to_string())?;
+        let font = tokio::fs::read(font_path).await.map_err(|e| {
+            eprintln!("Failed to read caption font {}: {e}", font_path.display());
+            "captions are not configured on this server".to_string()
+        })?;
+        Some(CaptionStyle {
+            font,
+            size: CAPTION_SIZE,
+            color: [0, 0, 0, 255],
+        })
+    } else {
+        None
+    };
+
+    Ok(ContactSheetOptions {
+        columns: request
+            .columns
+            .unwrap_or(defaults.columns)
+            .clamp(1, MAX_COLUMNS),
+        cell_width: request
+            .cell_width
+            .unwrap_or(defaults.cell_width)
+            .clamp(1, MAX_CELL_SIZE),
+        cell_height: request
+            .cell_height
+            .unwrap_or(defaults.cell_height)
+            .clamp(1, MAX_CELL_SIZE),
+        padding: request
+            .padding
+            .unwrap_or(defaults.padding)
+            .min(MAX_CELL_SIZE),
+        background,
+        captions,
+        format,
+    })
+}
+
+// Parses `#rrggbb` or `#rrggbbaa`.
+fn parse_color(color: &str) -> Option<[u8; 4]> {
+    let hex = color.strip_prefix('#')?;
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    let alpha = if hex.len() == 8 { channel(6)? } else { u8::MAX };
+    Some([channel(0)?, channel(2)?, channel(4)?, alpha])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::{Body, Bytes};
+    use axum::http::{header, Request};
+    use tower::ServiceExt;
+
+    use crate::config::AdminToken;
+    use crate::repository::image_repository::NewImage;
+    use crate::test_support::{app_state, new_image};
+
+    fn png(width: u32, height: u32) -> Bytes {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(width, height)
+            .write_to(&mut data, image::ImageFormat::Png)
+            .unwrap();
+        Bytes::from(data.into_inner())
+    }
+
+    #[tokio::test]
+    async fn only_operators_can_compose_sheets() {
+        let config = Config {
+            admin_token: Some(AdminToken::new("secret")),
+            ..Config::default()
+        };
+        let state = app_state(config).await;
+        let new_image = NewImage {
+            tags: "sheet".to_string(),
+            ..new_image("image/png")
+        };
+        let id = state.insert(&new_image).await.unwrap();
+        state
+            .blob_store()
+            .put(&original_key(id), png(40, 20))
+            .await
+            .unwrap();
+        let router = contact_sheet_routes(state);
+
+        let request = |token: Option<&str>| {
+            let mut request =
+                Request::post("/contact-sheet").header(header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            request.body(Body::from(r#"{"tags":"sheet"}"#)).unwrap()
+        };
+        let response = router.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = router.oneshot(request(Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["cells"][0]["id"], id);
+        assert_eq!(body["cells"][0]["image"]["width"], 160);
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("#ff8000"), Some([255, 128, 0, 255]));
+        assert_eq!(parse_color("#00000080"), Some([0, 0, 0, 128]));
+        assert_eq!(parse_color("ff8000"), None);
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("#gg0000"), None);
+    }
+
+    #[tokio::test]
+    async fn request_options_are_clamped() {
+        let request = ContactSheetRequest {
+            columns: Some(0),
+            cell_width: Some(10_000),
+            format: Some("jpg".to_string()),
+            ..ContactSheetRequest::default()
+        };
+
+        let options = sheet_options(&request, &Config::default()).await.unwrap();
+
+        assert_eq!(options.columns, 1);
+        assert_eq!(options.cell_width, MAX_CELL_SIZE);
+        assert_eq!(options.format, ThumbnailFormat::Jpeg);
+    }
+
+    #[tokio::test]
+    async fn unknown_format_is_rejected() {
+        let request = ContactSheetRequest {
+            format: Some("bmp".to_string()),
+            ..ContactSheetRequest::default()
+        };
+
+        assert!(sheet_options(&request, &Config::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn captions_need_a_configured_font() {
+        let request = ContactSheetRequest {
+            captions: Some(true),
+            ..ContactSheetRequest::default()
+        };
+
+        let error = sheet_options(&request, &Config::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error, "captions are not configured on this server");
+    }
+
+    #[tokio::test]
+    async fn sheets_too_large_as_a_whole_are_rejected() {
+        let request = ContactSheetRequest {
+            columns: Some(MAX_COLUMNS),
+            cell_width: Some(MAX_CELL_SIZE),
+            cell_height: Some(MAX_CELL_SIZE),
+            padding: Some(MAX_CELL_SIZE),
+            ..ContactSheetRequest::default()
+        };
+        let options = sheet_options(&request, &Config::default()).await.unwrap();
+
+        assert!(check_sheet_size(1, &options).is_ok());
+        assert!(check_sheet_size(MAX_SHEET_IMAGES, &options).is_err());
+        assert!(check_sheet_size(MAX_SHEET_IMAGES, &ContactSheetOptions::default()).is_ok());
+    }
+}
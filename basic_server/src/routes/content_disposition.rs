@@ -0,0 +1,185 @@
+// `Content-Disposition` values for served files (RFC 6266).
+
+/// Whether the browser should display the file or save it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    /// `?download=1` (or `true`) asks for an attachment.
+    pub fn from_download_param(download: Option<&str>) -> Self {
+        match download {
+            Some("1") | Some("true") => Disposition::Attachment,
+            _ => Disposition::Inline,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Disposition::Inline => "inline",
+            Disposition::Attachment => "attachment",
+        }
+    }
+}
+
+/// Builds a header value such as
+/// `attachment; filename="na_ve.png"; filename*=UTF-8''na%C3%AFve.png`.
+/// The quoted `filename` is an ASCII fallback for old clients; `filename*`
+/// carries the exact name.
+pub fn content_disposition(disposition: Disposition, file_name: &str) -> String {
+    let file_name = sanitize_file_name(file_name);
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    if fallback == file_name {
+        return format!("{}; filename=\"{fallback}\"", disposition.as_str());
+    }
+    format!(
+        "{}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        disposition.as_str(),
+        percent_encode(&file_name)
+    )
+}
+
+/// Keeps only the last path component of a client-supplied name and drops
+/// control characters, so it is safe to echo back in a header.
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let base = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    base.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// The file name a client sent in a request's `Content-Disposition`, such as
+/// `attachment; filename="scan.png"`, preferring `filename*`. Sanitized like
+/// uploaded names.
+pub fn requested_file_name(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for parameter in value.split(';').skip(1) {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename" => plain = Some(unquote(value.trim())),
+            "filename*" => extended = decode_extended(value.trim()),
+            _ => {}
+        }
+    }
+    extended
+        .or(plain)
+        .map(|name| sanitize_file_name(&name))
+        .filter(|name| !name.is_empty())
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+// RFC 5987 `UTF-8''<percent-encoded>`; other charsets are ignored.
+fn decode_extended(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut input = encoded.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// RFC 5987 `value-chars`: attr-chars stay, everything else is %-encoded.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_names_are_quoted() {
+        assert_eq!(
+            content_disposition(Disposition::Inline, "cat.png"),
+            "inline; filename=\"cat.png\""
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_are_encoded() {
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "naïve \"cat\".png"),
+            "attachment; filename=\"na_ve _cat_.png\"; filename*=UTF-8''na%C3%AFve%20%22cat%22.png"
+        );
+    }
+
+    #[test]
+    fn directories_are_stripped() {
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\a\nb.jpg"), "ab.jpg");
+    }
+
+    #[test]
+    fn request_file_names_are_read_and_sanitized() {
+        assert_eq!(
+            requested_file_name("attachment; filename=\"scan.png\"").as_deref(),
+            Some("scan.png")
+        );
+        assert_eq!(
+            requested_file_name(
+                "attachment; filename=\"na_ve.png\"; filename*=UTF-8''na%C3%AFve.png"
+            )
+            .as_deref(),
+            Some("naïve.png")
+        );
+        assert_eq!(
+            requested_file_name("inline; filename=../x.gif").as_deref(),
+            Some("x.gif")
+        );
+        assert_eq!(requested_file_name("attachment"), None);
+        assert_eq!(requested_file_name("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn download_param_selects_attachment() {
+        assert_eq!(
+            Disposition::from_download_param(Some("1")),
+            Disposition::Attachment
+        );
+        assert_eq!(Disposition::from_download_param(None), Disposition::Inline);
+        assert_eq!(
+            Disposition::from_download_param(Some("0")),
+            Disposition::Inline
+        );
+    }
+}
@@ -0,0 +1,360 @@
+// Validators and conditional-request handling for served blobs.
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use axum::http::{header, HeaderMap};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::hex;
+use crate::storage::{BlobMetadata, BlobStore, BlobStream};
+
+/// `Cache-Control` for URLs pinned to a content version with `?v=<etag>`.
+pub const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// How a served file may be cached by clients.
+#[derive(Debug, Clone, Copy)]
+pub struct Caching<'a> {
+    pub cache_control: &'a str,
+    /// The `v` query parameter; when it matches the current ETag the
+    /// response never changes and is marked immutable.
+    pub version: Option<&'a str>,
+}
+
+impl<'a> Caching<'a> {
+    pub fn new(cache_control: &'a str, version: Option<&'a str>) -> Self {
+        Self {
+            cache_control,
+            version,
+        }
+    }
+
+    pub fn cache_control(&self, etag: &str) -> &'a str {
+        match self.version {
+            Some(version) if etag.trim_matches('"') == version => CACHE_CONTROL_IMMUTABLE,
+            _ => self.cache_control,
+        }
+    }
+}
+
+/// Strong validators of a file on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// Quoted, e.g. `"3f2a…"`.
+    pub etag: String,
+    pub last_modified: SystemTime,
+    /// File size, reported alongside so callers need no second `stat`.
+    pub len: u64,
+}
+
+impl Validators {
+    pub fn last_modified_header(&self) -> String {
+        httpdate::fmt_http_date(self.last_modified)
+    }
+
+    /// Whether a conditional request can be answered with `304 Not Modified`.
+    /// `If-None-Match` takes precedence over `If-Modified-Since` (RFC 9110).
+    pub fn is_not_modified(&self, request: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+
+        request
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            // HTTP dates have whole-second precision.
+            .is_some_and(|since| truncate_to_seconds(self.last_modified) <= since)
+    }
+
+    /// Whether a `Range` header should be honoured: without `If-Range`, or
+    /// when `If-Range` still names the current representation.
+    pub fn range_applies(&self, request: &HeaderMap) -> bool {
+        match request.get(header::IF_RANGE) {
+            None => true,
+            Some(if_range) => if_range.to_str().is_ok_and(|if_range| {
+                if_range == self.etag || if_range == self.last_modified_header()
+            }),
+        }
+    }
+}
+
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    httpdate::HttpDate::from(time).into()
+}
+
+/// Blobs whose content hash is remembered; the least recently served are
+/// forgotten first, so variants and deleted images do not pile up.
+const HASH_CACHE_CAPACITY: usize = 10_000;
+
+// Content hashes keyed by blob key, reused while size and mtime are unchanged.
+struct HashCache {
+    capacity: usize,
+    entries: HashMap<String, CachedHash>,
+    // Ticks on every use, to find the least recently used entry.
+    clock: u64,
+}
+
+struct CachedHash {
+    len: u64,
+    last_modified: SystemTime,
+    etag: String,
+    last_used: u64,
+}
+
+impl HashCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str, len: u64, last_modified: SystemTime) -> Option<String> {
+        self.clock += 1;
+        let entry = self
+            .entries
+            .get_mut(key)
+            .filter(|entry| entry.len == len && entry.last_modified == last_modified)?;
+        entry.last_used = self.clock;
+        Some(entry.etag.clone())
+    }
+
+    fn insert(&mut self, key: String, len: u64, last_modified: SystemTime, etag: String) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // A linear scan, but only after hashing a whole blob anyway.
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        let entry = CachedHash {
+            len,
+            last_modified,
+            etag,
+            last_used: self.clock,
+        };
+        self.entries.insert(key, entry);
+    }
+}
+
+fn hash_cache() -> &'static Mutex<HashCache> {
+    static CACHE: OnceLock<Mutex<HashCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashCache::new(HASH_CACHE_CAPACITY)))
+}
+
+/// Returns the validators of a blob, or `None` if it does not exist.
+///
+/// `sha256` is the hex SHA-256 recorded when the blob was written, as for
+/// originals; the ETag is taken from it without reading the blob. Other
+/// blobs are hashed, and only when they changed since they were last seen.
+pub async fn blob_validators(
+    store: &dyn BlobStore,
+    key: &str,
+    sha256: Option<&str>,
+) -> Result<Option<Validators>> {
+    let Some(BlobMetadata { len, last_modified }) = store.metadata(key).await? else {
+        return Ok(None);
+    };
+
+    let cached = match sha256 {
+        Some(sha256) => Some(sha256_etag(sha256)),
+        None => hash_cache().lock().unwrap().get(key, len, last_modified),
+    };
+    let etag = match cached {
+        Some(etag) => etag,
+        None => {
+            let Some(stream) = store.get(key).await? else {
+                return Ok(None);
+            };
+            let etag = content_etag(stream).await?;
+            hash_cache()
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), len, last_modified, etag.clone());
+            etag
+        }
+    };
+
+    Ok(Some(Validators {
+        etag,
+        last_modified,
+        len,
+    }))
+}
+
+// First 128 bits of the SHA-256 of the content, hex encoded and quoted.
+async fn content_etag(mut stream: BlobStream) -> Result<String> {
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(&chunk?);
+    }
+    Ok(sha256_etag(&hex::encode(&hasher.finalize())))
+}
+
+// The ETag of content with the given hex SHA-256.
+fn sha256_etag(sha256: &str) -> String {
+    format!("\"{}\"", sha256.get(..32).unwrap_or(sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use axum::http::HeaderValue;
+
+    use crate::storage::memory::MemoryBlobStore;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc\"".to_string(),
+            last_modified: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+            len: 3,
+        }
+    }
+
+    fn request(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn hash_cache_forgets_the_least_recently_used_blob() {
+        let mut cache = HashCache::new(2);
+        let modified = SystemTime::UNIX_EPOCH;
+        cache.insert("a".to_string(), 1, modified, "\"a\"".to_string());
+        cache.insert("b".to_string(), 1, modified, "\"b\"".to_string());
+        assert!(cache.get("a", 1, modified).is_some());
+
+        cache.insert("c".to_string(), 1, modified, "\"c\"".to_string());
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get("b", 1, modified).is_none());
+        assert_eq!(cache.get("a", 1, modified).as_deref(), Some("\"a\""));
+        // A changed blob is not served from the cache.
+        assert!(cache.get("c", 2, modified).is_none());
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let validators = validators();
+
+        assert!(validators.is_not_modified(&request(header::IF_NONE_MATCH, "\"x\", \"abc\"")));
+        assert!(validators.is_not_modified(&request(header::IF_NONE_MATCH, "W/\"abc\"")));
+        assert!(validators.is_not_modified(&request(header::IF_NONE_MATCH, "*")));
+        assert!(!validators.is_not_modified(&request(header::IF_NONE_MATCH, "\"abd\"")));
+        assert!(!validators.is_not_modified(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn if_modified_since_uses_whole_seconds() {
+        let validators = validators();
+        let date = validators.last_modified_header();
+
+        assert!(validators.is_not_modified(&request(header::IF_MODIFIED_SINCE, &date)));
+        assert!(!validators.is_not_modified(&request(
+            header::IF_MODIFIED_SINCE,
+            "Thu, 01 Jan 2015 00:00:00 GMT"
+        )));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let validators = validators();
+        let mut headers = request(header::IF_NONE_MATCH, "\"other\"");
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&validators.last_modified_header()).unwrap(),
+        );
+
+        assert!(!validators.is_not_modified(&headers));
+    }
+
+    #[test]
+    fn if_range_must_match_current_version() {
+        let validators = validators();
+
+        assert!(validators.range_applies(&HeaderMap::new()));
+        assert!(validators.range_applies(&request(header::IF_RANGE, "\"abc\"")));
+        assert!(!validators.range_applies(&request(header::IF_RANGE, "\"old\"")));
+    }
+
+    #[test]
+    fn pinned_versions_are_immutable() {
+        let caching = Caching::new("public, max-age=60", Some("abc"));
+
+        assert_eq!(caching.cache_control("\"abc\""), CACHE_CONTROL_IMMUTABLE);
+        assert_eq!(caching.cache_control("\"abd\""), "public, max-age=60");
+    }
+
+    #[tokio::test]
+    async fn etag_follows_content() {
+        let store = MemoryBlobStore::new();
+        store
+            .put("a.jpg", Bytes::from_static(b"one"))
+            .await
+            .unwrap();
+        let first = blob_validators(&store, "a.jpg", None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        store
+            .put("a.jpg", Bytes::from_static(b"two!"))
+            .await
+            .unwrap();
+        let second = blob_validators(&store, "a.jpg", None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_ne!(first.etag, second.etag);
+        assert_eq!(first.etag.len(), 34);
+        assert!(blob_validators(&store, "b.jpg", None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn recorded_hashes_are_used_without_reading_the_blob() {
+        let store = MemoryBlobStore::new();
+        let content = Bytes::from_static(b"one");
+        store.put("a.jpg", content.clone()).await.unwrap();
+        let hashed = blob_validators(&store, "a.jpg", None)
+            .await
+            .unwrap()
+            .unwrap();
+        let sha256 = crate::service::staged_upload::sha256_hex(&content);
+        let recorded = blob_validators(&store, "a.jpg", Some(&sha256))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recorded, hashed);
+
+        // A replacement of the same size in the same instant gets the ETag
+        // of its new hash.
+        let recorded = blob_validators(&store, "a.jpg", Some(&"ab".repeat(32)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recorded.etag, format!("\"{}\"", "ab".repeat(16)));
+    }
+}
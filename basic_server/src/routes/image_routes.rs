@@ -1,36 +1,115 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use axum::body::Body;
-use axum::extract::Path as Path2;
-use axum::http::{header, StatusCode};
+use axum::extract::{DefaultBodyLimit, Request};
+use axum::extract::{Path as Path2, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware;
 use axum::response::Response;
+use axum::RequestExt;
 use axum::{
-    extract::{multipart::Field, Multipart, State},
+    extract::{
+        multipart::{Field, MultipartError, MultipartRejection},
+        Multipart, State,
+    },
     response::{Html, IntoResponse},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use thumbnail::{Thumbnail, ThumbnailError};
-use tokio::fs::{read_to_string, File, OpenOptions};
-use tokio::io::AsyncWriteExt;
-use tokio::task::spawn_blocking;
-use tokio_util::io::ReaderStream;
+use serde::{Deserialize, Serialize};
+use tempfile::TempPath;
+use thumbnail::{Thumbnail, ThumbnailError, ThumbnailOptions};
+use tokio::fs::read_to_string;
 
-use crate::repository::image_repository::{Image, ImageFilter, ImageRepository, ImageResult};
+use crate::config::{Config, ConfigProvider, Preset};
+use crate::repository::image_repository::{
+    Image, ImageFilter, ImageRepository, ImageResult, NewImage,
+};
+use crate::repository::job_repository::{JobRepository, NewJob};
+use crate::repository::preset_repository::PresetRepository;
+use crate::routes::admin_routes::require_admin_token;
+use crate::routes::api_error::{api_error, validation_failed, FieldError};
+use crate::routes::byte_ranges::{
+    content_range, multipart_body, parse_range, range_body, RangeRequest,
+};
+use crate::routes::content_disposition::{
+    content_disposition, requested_file_name, sanitize_file_name, Disposition,
+};
+use crate::routes::http_cache::{blob_validators, Caching};
+use crate::routes::job_routes::{image_status_url, job_url};
+use crate::routes::signed_urls::require_signature;
+use crate::service::image_executor::{ImageExecutor, ImageExecutorProvider, Priority};
+use crate::service::image_service::{
+    negotiate_thumbnail_format, resolve_variant, variant_file_name, VariantQuery,
+};
+use crate::service::staged_upload::{stage_upload, StageError, StagedUpload};
+use crate::storage::{derived_key_prefix, original_key, BlobStore, BlobStoreProvider};
 
-const CONTENT_TYPE_JPEG: &str = "image/jpeg";
 const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
 
-pub fn image_routes<T: ImageRepository>(repository: Arc<T>) -> Router {
+/// Query parameters shared by all media routes.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct MediaQuery {
+    /// `?v=<etag>` pins the URL to one version of the content.
+    pub v: Option<String>,
+    /// `?download=1` serves the file as an attachment.
+    pub download: Option<String>,
+}
+
+impl MediaQuery {
+    pub fn caching<'a>(&'a self, cache_control: &'a str) -> Caching<'a> {
+        Caching::new(cache_control, self.v.as_deref())
+    }
+
+    pub fn disposition(&self) -> Disposition {
+        Disposition::from_download_param(self.download.as_deref())
+    }
+}
+
+pub fn image_routes<T>(repository: Arc<T>) -> Router
+where
+    T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    // Routes serving image data may require signed URLs.
+    let media = Router::new()
+        .route("/images/:id", get(get_image))
+        .route("/thumbnails/:id", get(get_thumbnail))
+        .route("/thumbnails/:id/:preset", get(get_preset_thumbnail))
+        .route_layer(middleware::from_fn_with_state(
+            repository.clone(),
+            require_signature::<T>,
+        ));
+
+    // Uploads are streamed to disk, so they may be far larger than the
+    // bodies of other requests.
+    let upload_limit = DefaultBodyLimit::max(repository.config().max_upload_size);
+    let uploads = Router::new()
+        .route("/images/upload", post(upload_handler))
+        .route("/api/images", post(create_image))
+        .layer(upload_limit);
+
+    // Changing or deleting existing images is for operators only.
+    let edits = Router::new()
+        .route("/images/:id", delete(delete_image).patch(patch_image))
+        .route(
+            "/images/:id/file",
+            put(replace_image_file).layer(upload_limit),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            repository.clone(),
+            require_admin_token::<T>,
+        ));
+
     Router::new()
         .route("/images/count", get(count_images))
-        .route("/images/upload", post(upload_handler))
-        .route("/images/:id", get(get_image))
         .route("/images", get(show_images))
-        .route("/thumbnails/:id", get(get_thumbnail))
+        .merge(uploads)
+        .merge(edits)
+        .merge(media)
         .with_state(repository)
 }
 
@@ -38,301 +117,1177 @@ async fn count_images<T: ImageRepository>(State(repo): State<Arc<T>>) -> String
     repo.count().await
 }
 
-async fn insert_image_into_db<T: ImageRepository>(repo: Arc<T>, tags: &str) -> Result<i64> {
-    repo.insert(tags).await
+async fn insert_image_into_db<T: ImageRepository>(repo: Arc<T>, image: &NewImage) -> Result<i64> {
+    repo.insert(image).await
 }
 
-async fn store_image(image_id: i64, data: &[u8]) -> Result<()> {
-    let file_path = Path::new("../images/").join(format!("{image_id}.jpg"));
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&file_path)
-        .await
-        .context("Failed to open file for writing")?;
-    file.write_all(data)
-        .await
-        .context("Failed to write data to file")
+async fn get_thumbnail<T: ConfigProvider + BlobStoreProvider + ImageExecutorProvider>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+    Query(query): Query<VariantQuery>,
+    Query(media): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let caching = media.caching(&repo.config().cache_control_variants);
+    let disposition = media.disposition();
+
+    if !query.is_empty() {
+        return match resolve_variant(&query, accept, repo.config()) {
+            Ok(variant) => {
+                let response = get_thumbnail_variant(
+                    &*repo,
+                    id,
+                    variant.options,
+                    &headers,
+                    caching,
+                    disposition,
+                )
+                .await;
+                if variant.negotiated {
+                    vary_on_accept(response)
+                } else {
+                    response
+                }
+            }
+            Err(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+        };
+    }
+
+    // The default preset, in whichever format the client prefers.
+    let preset = repo.config().default_preset();
+    let format = negotiate_thumbnail_format(accept);
+    let options = ThumbnailOptions {
+        format,
+        quality: format.normalize_quality(Some(preset.quality)),
+        ..preset
+    };
+    vary_on_accept(get_thumbnail_variant(&*repo, id, options, &headers, caching, disposition).await)
 }
 
-async fn get_thumbnail(Path2(id): Path2<i64>) -> impl IntoResponse {
-    let filename = format!("../images/{id}_thumbnail.jpg");
-    let attachment = format!("filename={filename}");
-    open_file(filename, attachment).await
+async fn get_preset_thumbnail<T: ConfigProvider + BlobStoreProvider + ImageExecutorProvider>(
+    State(repo): State<Arc<T>>,
+    Path2((id, preset)): Path2<(i64, String)>,
+    Query(media): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let caching = media.caching(&repo.config().cache_control_variants);
+    match repo.config().preset(&preset) {
+        Some(preset) => {
+            let disposition = media.disposition();
+            get_thumbnail_variant(&*repo, id, preset.options, &headers, caching, disposition).await
+        }
+        None => not_found().await,
+    }
 }
-async fn get_image(Path2(id): Path2<i64>) -> impl IntoResponse {
-    let filename = format!("../images/{id}.jpg");
-    let attachment = format!("filename={filename}");
-    open_file(filename, attachment).await
+
+// The body depends on the Accept header, so shared caches must key on it too.
+fn vary_on_accept(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("accept"));
+    response
 }
 
-async fn open_file(filename: String, attachment: String) -> impl IntoResponse {
-    match File::open(&filename).await {
-        Ok(file) => {
-            let reader = ReaderStream::new(file);
-            let stream_body = Body::from_stream(reader);
-            Response::builder()
-                .header(
-                    header::CONTENT_TYPE,
-                    header::HeaderValue::from_static("image/jpeg"),
-                )
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    header::HeaderValue::from_str(&attachment).unwrap(),
-                )
-                .body(stream_body)
-                .unwrap_or_else(|_| Response::default())
+// Serves a preset or a query-driven variant such as `?w=320&h=240&fit=cover`,
+// rendering it on first request and caching it in the blob store under its
+// normalized parameters. A changed preset definition therefore maps to a new key.
+async fn get_thumbnail_variant<T: BlobStoreProvider + ImageExecutorProvider>(
+    repo: &T,
+    id: i64,
+    options: ThumbnailOptions,
+    request: &HeaderMap,
+    caching: Caching<'_>,
+    disposition: Disposition,
+) -> Response<Body> {
+    let store = repo.blob_store();
+    let key = variant_file_name(id, &options);
+
+    if !store.exists(&key).await.unwrap_or(false) {
+        if let Err(e) = render_variant(repo.image_executor(), store, id, &key, options).await {
+            eprintln!("Failed to create variant {key}: {e}");
         }
-        Err(_) => not_found().await,
     }
+
+    serve_blob(
+        store,
+        &key,
+        None,
+        content_disposition(disposition, &key),
+        options.format.mime_type(),
+        request,
+        caching,
+    )
+    .await
 }
 
-async fn not_found() -> Response<Body> {
+async fn render_variant(
+    executor: &ImageExecutor,
+    store: &dyn BlobStore,
+    id: i64,
+    key: &str,
+    options: ThumbnailOptions,
+) -> Result<()> {
+    let original_key = original_key(id);
+    let data = store
+        .get_bytes(&original_key)
+        .await?
+        .ok_or(ThumbnailError::NotFound(original_key))?;
+    // A client is waiting for this one.
+    let mut variants = executor
+        .run(Priority::Interactive, move || {
+            Thumbnail::make_variants_from_memory(&data, &[options])
+        })
+        .await??;
+    let variant = variants.pop().context("no variant rendered")?;
+    store.put(key, variant.data.into()).await
+}
+
+async fn get_image<T: ImageRepository + ConfigProvider + BlobStoreProvider>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+    Query(media): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let image = match find_image(repo.as_ref(), id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return not_found().await,
+        Err(e) => {
+            eprintln!("Failed to look up image {id}: {e}");
+            return not_found().await;
+        }
+    };
+
+    let disposition = content_disposition(media.disposition(), &image.download_name());
+    let caching = media.caching(&repo.config().cache_control_originals);
+    serve_blob(
+        repo.blob_store(),
+        &original_key(id),
+        image.sha256.as_deref(),
+        disposition,
+        &image.mime_type,
+        &headers,
+        caching,
+    )
+    .await
+}
+
+pub(crate) async fn find_image<T: ImageRepository + ?Sized>(
+    repo: &T,
+    id: i64,
+) -> Result<Option<Image>> {
+    let filter = ImageFilter {
+        id: Some(id),
+        tags: None,
+        thumbnail_status: None,
+        sha256: None,
+    };
+    match repo.filter(filter).await? {
+        ImageResult::Single(image) => Ok(Some(image)),
+        ImageResult::Multiple(images) => Ok(images.into_iter().find(|image| image.id == id)),
+    }
+}
+
+// Streams a blob with ETag, Last-Modified and Cache-Control headers, or
+// answers `304 Not Modified` when the client's copy is still current. `Range`
+// requests get `206 Partial Content` (one part, or `multipart/byteranges`)
+// or `416 Range Not Satisfiable`. `sha256` is the content hash recorded when
+// the blob was written, if there is one.
+pub(crate) async fn serve_blob(
+    store: &dyn BlobStore,
+    key: &str,
+    sha256: Option<&str>,
+    disposition: String,
+    content_type: &str,
+    request: &HeaderMap,
+    caching: Caching<'_>,
+) -> Response<Body> {
+    let validators = match blob_validators(store, key, sha256).await {
+        Ok(Some(validators)) => validators,
+        Ok(None) => return not_found().await,
+        Err(e) => {
+            eprintln!("Failed to read validators of {key}: {e}");
+            return not_found().await;
+        }
+    };
+    let len = validators.len;
+
+    let builder = Response::builder()
+        .header(header::ETAG, &validators.etag)
+        .header(header::LAST_MODIFIED, validators.last_modified_header())
+        .header(
+            header::CACHE_CONTROL,
+            caching.cache_control(&validators.etag),
+        )
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if validators.is_not_modified(request) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap_or_else(|_| Response::default());
+    }
+
+    let builder = builder.header(
+        header::CONTENT_DISPOSITION,
+        header::HeaderValue::from_str(&disposition).unwrap(),
+    );
+
+    let range = match validators.range_applies(request) {
+        true => parse_range(
+            request
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok()),
+            len,
+        ),
+        false => RangeRequest::Full,
+    };
+    let response = match range {
+        RangeRequest::Full => match store.get(key).await {
+            Ok(Some(stream)) => builder
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, len)
+                .body(Body::from_stream(stream)),
+            Ok(None) => return not_found().await,
+            Err(e) => {
+                eprintln!("Failed to read {key}: {e}");
+                return not_found().await;
+            }
+        },
+        RangeRequest::Unsatisfiable => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .body(Body::empty()),
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_RANGE, content_range(&range, len))
+                .header(header::CONTENT_LENGTH, range.end - range.start);
+            match range_body(store, key, range).await {
+                Ok(body) => builder.body(body),
+                Err(e) => {
+                    eprintln!("Failed to read range of {key}: {e}");
+                    return not_found().await;
+                }
+            }
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = format!("range_{}", validators.etag.trim_matches('"'));
+            match multipart_body(store, key, &ranges, content_type, len, &boundary).await {
+                Ok((body, body_len)) => builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(
+                        header::CONTENT_TYPE,
+                        format!("multipart/byteranges; boundary={boundary}"),
+                    )
+                    .header(header::CONTENT_LENGTH, body_len)
+                    .body(body),
+                Err(e) => {
+                    eprintln!("Failed to read ranges of {key}: {e}");
+                    return not_found().await;
+                }
+            }
+        }
+    };
+    response.unwrap_or_else(|_| Response::default())
+}
+
+pub(crate) async fn not_found() -> Response<Body> {
     let path_error = Path::new("./src/templates/file_not_found.html");
     match read_to_string(&path_error).await {
         Ok(content) => Response::builder()
             .status(StatusCode::NOT_FOUND)
-            .header("Content-Type", "text/html; charset=utf-8")
+            .header(header::CONTENT_TYPE, CONTENT_TYPE_HTML)
             .body(Body::from(content))
             .unwrap(),
         Err(_) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("Content-Type", "text/html; charset=utf-8")
+            .header(header::CONTENT_TYPE, CONTENT_TYPE_HTML)
             .body(Body::from("Error page not found."))
             .unwrap(),
     }
 }
 
-pub async fn fill_missing_thumbnails<T: ImageRepository>(repo: Arc<T>) -> Result<()> {
+// Renders every preset of an image from its original and stores the results.
+pub(crate) async fn generate_presets<T: BlobStoreProvider + ImageExecutorProvider>(
+    repo: Arc<T>,
+    id: i64,
+    presets: Vec<Preset>,
+    priority: Priority,
+) -> Result<()> {
+    let store = repo.blob_store();
+    let original_key = original_key(id);
+    let data = store
+        .get_bytes(&original_key)
+        .await?
+        .ok_or(ThumbnailError::NotFound(original_key))?;
+
+    let options: Vec<ThumbnailOptions> = presets.iter().map(|preset| preset.options).collect();
+    let rendered = options.clone();
+    let variants = repo
+        .image_executor()
+        .run(priority, move || {
+            Thumbnail::make_variants_from_memory(&data, &rendered)
+        })
+        .await??;
+    for (options, variant) in options.iter().zip(variants) {
+        store
+            .put(&variant_file_name(id, options), variant.data.into())
+            .await?;
+    }
+    Ok(())
+}
+
+// Removes the cached variants and tiles of an image, which all share its
+// `{id}_` key prefix.
+pub(crate) async fn delete_derived_blobs(store: &dyn BlobStore, id: i64) -> Result<()> {
+    for key in store.list(&derived_key_prefix(id)).await? {
+        store.delete(&key).await?;
+    }
+    Ok(())
+}
+
+/// Queues regeneration of the presets whose definition changed (or that are
+/// new) since the last start, for every image, and records the definitions.
+pub async fn sync_thumbnail_presets<
+    T: ImageRepository + PresetRepository + JobRepository + ConfigProvider,
+>(
+    repo: Arc<T>,
+) -> Result<()> {
+    let stored = repo.preset_definitions().await?;
+    let changed = repo.config().changed_presets(&stored);
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let names: Vec<&str> = changed.iter().map(|preset| preset.name.as_str()).collect();
+    println!("Regenerating presets {names:?}");
+
     let image_filter = ImageFilter {
         id: None,
         tags: None,
-        thumbnail: Some(false),
+        thumbnail_status: None,
+        sha256: None,
     };
-
     let images = match repo.filter(image_filter).await? {
         ImageResult::Multiple(images) => images,
-        _ => Vec::with_capacity(0),
+        ImageResult::Single(image) => vec![image],
     };
 
-    if images.is_empty() {
-        println!("nothing to update");
-        return Ok(());
+    let names: Vec<String> = changed.iter().map(|preset| preset.name.clone()).collect();
+    for image in images {
+        let job = NewJob {
+            image_id: image.id,
+            presets: Some(names.clone()),
+            priority: Priority::Background,
+        };
+        repo.enqueue(&job).await?;
     }
 
-    let mut handles = Vec::with_capacity(images.len());
-    let mut to_delete: Vec<i64> = Vec::new();
+    // Safe to record now: the queued jobs survive a restart.
+    for preset in &changed {
+        repo.save_preset_definition(&preset.name, &preset.definition())
+            .await?;
+    }
 
-    // Todo UPDATE image THUMBNAIL = 1
-    // ATM every time the app starts it wants to create thumbnails
-    for image in &images {
-        let id = image.id;
-        let handle = spawn_blocking(move || {
-            let file_path = Path::new("../images/").join(format!("{id}.jpg"));
-            let thumbnail_path = Path::new("../images/").join(format!("{id}_thumbnail.jpg"));
-            match Thumbnail::make_thumbnail(file_path, thumbnail_path) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
-            }
-        });
-        handles.push((id, handle));
+    Ok(())
+}
+
+// Accepts one image, or a batch when the form has several `file` parts. A
+// `tags` field applies to every file; `tags[<n>]` only to the n-th file
+// (counting from 0), whichever order the fields come in.
+async fn upload_handler<T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider>(
+    State(repo): State<Arc<T>>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Response {
+    let html = prefers_html(&headers);
+    let form = match read_form(multipart, repo.config()).await {
+        Ok(form) if form.files.len() > 1 => return upload_batch(repo, form, html).await,
+        Ok(form) => form.into_single().map_err(UploadRejection::Invalid),
+        Err(rejection) => Err(rejection),
+    };
+    let (new_image, file) = match form {
+        Ok(upload) => upload,
+        Err(rejection) if html => {
+            let message = rejection.to_string();
+            return upload_failed(html, rejection.status(), &message).await;
+        }
+        Err(rejection) => return rejection.into_response(),
+    };
+    let file_name = new_image.file_name.clone();
+    let accepted = match accept_upload(repo, &new_image, file).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            eprintln!("Failed to accept upload: {e:#}");
+            let message = "the upload could not be stored";
+            return upload_failed(html, StatusCode::INTERNAL_SERVER_ERROR, message).await;
+        }
+    };
+
+    match (accepted, html) {
+        (Accepted::Created(accepted), false) => {
+            (StatusCode::ACCEPTED, Json(accepted)).into_response()
+        }
+        (Accepted::Created(_), true) => upload_succeeded().await,
+        (duplicate, false) => Json(FileOutcome::from(duplicate)).into_response(),
+        (duplicate, true) => {
+            let result = FileResult {
+                file_name,
+                outcome: duplicate.into(),
+            };
+            upload_results(StatusCode::OK, &[result]).await
+        }
     }
+}
 
-    for (id, handle) in handles {
-        match handle.await? {
-            Ok(_) => println!("Thumbnail created successfully for ID {}", id),
-            Err(e) => {
-                if let Some(thumbnail_error) = e.downcast_ref::<ThumbnailError>() {
-                    match thumbnail_error {
-                        ThumbnailError::NotFound(_) => {
-                            println!("File not found, deleting from DB...");
-                            to_delete.push(id);
-                        }
-                        ThumbnailError::Processing(msg) => println!("Processing error: {}", msg),
+async fn upload_succeeded() -> Response {
+    let path_success = Path::new("./src/templates/upload.html");
+    match read_to_string(&path_success).await {
+        Ok(content) => Html(content).into_response(),
+        Err(_) => upload_failed(true, StatusCode::INTERNAL_SERVER_ERROR, "").await,
+    }
+}
+
+// The page listing what became of each uploaded file.
+async fn upload_results(status: StatusCode, results: &[FileResult]) -> Response {
+    let path_results = Path::new("./src/templates/upload_results.html");
+    let content = match read_to_string(&path_results).await {
+        Ok(content) => content,
+        Err(_) => return upload_failed(true, StatusCode::INTERNAL_SERVER_ERROR, "").await,
+    };
+    let items: String = results
+        .iter()
+        .map(|result| {
+            let name = result.file_name.as_deref().unwrap_or("(unnamed file)");
+            let outcome = match &result.outcome {
+                FileOutcome::Created(accepted) => {
+                    format!("uploaded as image {}", accepted.image_id)
+                }
+                FileOutcome::Duplicate { image_id } => {
+                    format!("already uploaded as image {image_id}")
+                }
+                FileOutcome::Rejected { reason, .. } => format!("rejected: {reason}"),
+            };
+            format!(
+                "    <li>{}: {}</li>\n",
+                escape_html(name),
+                escape_html(&outcome)
+            )
+        })
+        .collect();
+    (status, Html(content.replace("{{results}}", &items))).into_response()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// What became of one file of an upload.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum FileOutcome {
+    Created(UploadAccepted),
+    /// An image with the same content exists already; nothing was stored.
+    Duplicate {
+        image_id: i64,
+    },
+    Rejected {
+        reason: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<FieldError>,
+    },
+}
+
+impl From<Accepted> for FileOutcome {
+    fn from(accepted: Accepted) -> Self {
+        match accepted {
+            Accepted::Created(accepted) => FileOutcome::Created(accepted),
+            Accepted::Duplicate(image_id) => FileOutcome::Duplicate { image_id },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileResult {
+    file_name: Option<String>,
+    #[serde(flatten)]
+    outcome: FileOutcome,
+}
+
+/// Body of a batch upload: one result per `file` part, in request order.
+#[derive(Debug, Serialize)]
+struct BatchUploaded {
+    results: Vec<FileResult>,
+}
+
+// Accepts every valid file of a batch on its own, so one bad file does not
+// fail the rest.
+async fn upload_batch<T: ImageRepository + JobRepository + BlobStoreProvider>(
+    repo: Arc<T>,
+    form: UploadForm,
+    html: bool,
+) -> Response {
+    let mut results = Vec::new();
+    for mut part in form.files {
+        let tags = part.tags.take().or_else(|| form.tags.clone());
+        let file_name = part.file_name.clone();
+        let outcome = match validate_file(tags, Some(part)) {
+            Ok((new_image, file)) => match accept_upload(repo.clone(), &new_image, file).await {
+                Ok(accepted) => accepted.into(),
+                Err(e) => {
+                    eprintln!("Failed to accept upload: {e:#}");
+                    FileOutcome::Rejected {
+                        reason: "the file could not be stored".to_string(),
+                        fields: Vec::new(),
                     }
-                } else {
-                    println!("Unhandled error type.");
                 }
+            },
+            Err(fields) => FileOutcome::Rejected {
+                reason: UploadRejection::Invalid(fields.clone()).to_string(),
+                fields,
+            },
+        };
+        results.push(FileResult { file_name, outcome });
+    }
+
+    if !html {
+        return Json(BatchUploaded { results }).into_response();
+    }
+    let rejected = |result: &FileResult| matches!(result.outcome, FileOutcome::Rejected { .. });
+    let status = if results.iter().all(rejected) {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::OK
+    };
+    upload_results(status, &results).await
+}
+
+// An image with the given content, found by its checksum.
+async fn find_duplicate<T: ImageRepository + ?Sized>(
+    repo: &T,
+    sha256: &str,
+) -> Result<Option<i64>> {
+    let filter = ImageFilter {
+        id: None,
+        tags: None,
+        thumbnail_status: None,
+        sha256: Some(sha256.to_string()),
+    };
+    Ok(match repo.filter(filter).await? {
+        ImageResult::Single(image) => Some(image.id),
+        ImageResult::Multiple(images) => images.first().map(|image| image.id),
+    })
+}
+
+// Whether a write failed because another image has the same checksum.
+fn is_unique_violation(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::Database(e)) if e.is_unique_violation()
+    )
+}
+
+/// Body of `201 Created` from `POST /api/images`: the new image and where to
+/// follow its thumbnails.
+#[derive(Debug, Serialize)]
+struct ImageCreated {
+    #[serde(flatten)]
+    image: Image,
+    job_id: i64,
+    status_url: String,
+    job_url: String,
+}
+
+// The JSON flavour of `upload_handler`, for API clients. Content that is
+// stored already is answered with `200 OK` and the existing image.
+async fn create_image<T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider>(
+    State(repo): State<Arc<T>>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Response {
+    let multipart = match multipart {
+        Ok(multipart) => multipart,
+        Err(rejection) => return UploadRejection::Malformed(rejection.body_text()).into_response(),
+    };
+    // Batches go to `/images/upload`; this route answers with one image.
+    let upload = match read_form(multipart, repo.config()).await {
+        Ok(form) if form.files.len() > 1 => Err(UploadRejection::Invalid(vec![FieldError::new(
+            "file",
+            "must be a single file",
+        )])),
+        Ok(form) => form.into_single().map_err(UploadRejection::Invalid),
+        Err(rejection) => Err(rejection),
+    };
+    let (new_image, file) = match upload {
+        Ok(upload) => upload,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let created = async {
+        let accepted = accept_upload(repo.clone(), &new_image, file).await?;
+        let image_id = match &accepted {
+            Accepted::Created(accepted) => accepted.image_id,
+            Accepted::Duplicate(image_id) => *image_id,
+        };
+        let image = find_image(repo.as_ref(), image_id)
+            .await?
+            .context("the image vanished after its upload")?;
+        let location = format!("/images/{image_id}");
+        anyhow::Ok(match accepted {
+            Accepted::Created(accepted) => {
+                let created = ImageCreated {
+                    image,
+                    job_id: accepted.job_id,
+                    status_url: accepted.status_url,
+                    job_url: accepted.job_url,
+                };
+                (
+                    StatusCode::CREATED,
+                    [(header::LOCATION, location)],
+                    Json(created),
+                )
+                    .into_response()
+            }
+            Accepted::Duplicate(_) => {
+                (StatusCode::OK, [(header::LOCATION, location)], Json(image)).into_response()
             }
+        })
+    };
+    match created.await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Failed to accept upload: {e:#}");
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the upload could not be stored",
+            )
         }
     }
+}
 
-    // TODO if postgres use BULK operations
-    for id in &to_delete {
-        match repo.delete(*id).await {
-            Ok(_) => {
-                println!("Image {id} deleted successfully.");
+/// Why an upload form was not accepted.
+#[derive(Debug, PartialEq, Eq)]
+enum UploadRejection {
+    /// The body is not a readable `multipart/form-data` form.
+    Malformed(String),
+    /// The body exceeds `max_upload_size`, in bytes.
+    TooLarge(usize),
+    Invalid(Vec<FieldError>),
+    /// The upload could not be staged; not the client's fault.
+    Failed,
+}
+
+impl UploadRejection {
+    fn status(&self) -> StatusCode {
+        match self {
+            UploadRejection::Malformed(_) => StatusCode::BAD_REQUEST,
+            UploadRejection::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadRejection::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UploadRejection::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn from_multipart(error: MultipartError, config: &Config) -> Self {
+        if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            UploadRejection::TooLarge(config.max_upload_size)
+        } else {
+            UploadRejection::Malformed(error.body_text())
+        }
+    }
+}
+
+impl Display for UploadRejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadRejection::Malformed(reason) => write!(f, "malformed upload: {reason}"),
+            UploadRejection::TooLarge(limit) => {
+                write!(f, "the upload exceeds the limit of {limit} bytes")
             }
-            Err(e) => {
-                eprintln!("Failed to delete image {id}: {e}. Will attempt to delete later.");
-                // push the id back to a retry queue or log it to a file/database for later retry.
+            UploadRejection::Failed => f.write_str("the upload could not be stored"),
+            UploadRejection::Invalid(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| format!("`{}` {}", field.field, field.message))
+                    .collect();
+                f.write_str(&fields.join(", "))
             }
         }
     }
+}
 
-    Ok(())
+impl IntoResponse for UploadRejection {
+    fn into_response(self) -> Response {
+        match self {
+            UploadRejection::Invalid(fields) => validation_failed(fields),
+            _ => api_error(self.status(), self.to_string()),
+        }
+    }
 }
 
-async fn upload_handler<T: ImageRepository>(
-    State(repo): State<Arc<T>>,
+/// The `tags` field of an upload form, or why it could not be read.
+type TagsField = Result<String, FieldError>;
+
+/// An upload form with its files staged to disk.
+struct UploadForm {
+    /// `tags`, for files without their own.
+    tags: Option<TagsField>,
+    files: Vec<FilePart>,
+}
+
+struct FilePart {
+    file_name: Option<String>,
+    upload: StagedUpload,
+    /// `tags[<n>]` of this, the n-th file.
+    tags: Option<TagsField>,
+}
+
+impl UploadForm {
+    // The single file of a form, with its own tags or else the form's.
+    fn into_single(mut self) -> Result<(NewImage, TempPath), Vec<FieldError>> {
+        let mut part = self.files.pop();
+        let tags = part
+            .as_mut()
+            .and_then(|part| part.tags.take())
+            .or(self.tags);
+        validate_file(tags, part)
+    }
+}
+
+// Reads the `tags` and `file` fields of an upload form. Files are streamed to
+// temporary files in `config.upload_dir`.
+async fn read_form(
     mut multipart: Multipart,
-) -> Html<String> {
-    let mut tags = None;
-    let mut image_data = None;
-    //let mut file_name: Option<String> = None;
-
-    while let Ok(Some(field)) = multipart.next_field().await {
-        match field.name() {
-            Some("tags") => {
-                let bytes = field.bytes().await.expect("Failed to read bytes for tags");
-                tags = Some(
-                    String::from_utf8(bytes.to_vec()).expect("Failed to decode tags from UTF-8"),
-                );
-            }
+    config: &Config,
+) -> Result<UploadForm, UploadRejection> {
+    let mut form = UploadForm {
+        tags: None,
+        files: Vec::new(),
+    };
+    let mut file_tags = HashMap::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(UploadRejection::from_multipart(e, config)),
+        };
+        let name = field.name().map(str::to_string);
+        match name.as_deref() {
+            Some("tags") => form.tags = Some(read_tags(field, "tags").await),
             Some("file") => {
-                //file_name = field.file_name().map(|s| s.to_string());
-                let bytes = field.bytes().await.expect("Failed to read bytes for files");
-                image_data = Some(bytes.to_vec());
+                let file_name = field.file_name().map(sanitize_file_name);
+                let upload = match stage_upload(field, &config.upload_dir).await {
+                    Ok(upload) => upload,
+                    Err(StageError::Read(e)) => {
+                        return Err(UploadRejection::from_multipart(e, config))
+                    }
+                    Err(StageError::Write(e)) => {
+                        eprintln!("Failed to stage upload: {e}");
+                        return Err(UploadRejection::Failed);
+                    }
+                };
+                form.files.push(FilePart {
+                    file_name: file_name.filter(|name| !name.is_empty()),
+                    upload,
+                    tags: None,
+                });
             }
-            _ => eprintln!("Unsupported field received"),
+            Some(name) => match tags_index(name) {
+                Some(index) => {
+                    file_tags.insert(index, read_tags(field, name).await);
+                }
+                None => eprintln!("Unsupported field received"),
+            },
+            None => eprintln!("Unsupported field received"),
+        }
+    }
+    for (index, tags) in file_tags {
+        match form.files.get_mut(index) {
+            Some(part) => part.tags = Some(tags),
+            None => eprintln!("Ignoring tags[{index}]: the form has no such file"),
         }
     }
+    Ok(form)
+}
 
-    if let (Some(tags), Some(image)) = (tags, image_data) {
-        let image_id = insert_image_into_db(repo, &tags).await.unwrap();
-        println!("id is {}", image_id);
+async fn read_tags(field: Field<'_>, name: &str) -> TagsField {
+    field
+        .text()
+        .await
+        .map_err(|_| FieldError::new(name, "must be UTF-8 text"))
+}
 
-        store_image(image_id.clone(), &image)
-            .await
-            .expect("error while storing file");
-
-        spawn_blocking(move || {
-            let image_id = image_id.clone();
-            let file_path = Path::new("../images/").join(format!("{image_id}.jpg"));
-            let thumbnail_path = Path::new("../images/").join(format!("{image_id}_thumbnail.jpg"));
-            match Thumbnail::make_thumbnail(file_path, thumbnail_path) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
+// The file index of a `tags[<n>]` field name.
+fn tags_index(name: &str) -> Option<usize> {
+    name.strip_prefix("tags[")?.strip_suffix(']')?.parse().ok()
+}
+
+// Validates one file and its tags, collecting every problem rather than
+// stopping at the first.
+fn validate_file(
+    tags: Option<TagsField>,
+    part: Option<FilePart>,
+) -> Result<(NewImage, TempPath), Vec<FieldError>> {
+    let mut errors = Vec::new();
+    let tags = match tags {
+        Some(Ok(tags)) => Some(tags),
+        Some(Err(error)) => {
+            errors.push(error);
+            None
+        }
+        None => {
+            errors.push(FieldError::new("tags", "is required"));
+            None
+        }
+    };
+    let mime_type = match &part {
+        None => {
+            errors.push(FieldError::new("file", "is required"));
+            None
+        }
+        Some(part) if part.upload.size == 0 => {
+            errors.push(FieldError::new("file", "must not be empty"));
+            None
+        }
+        Some(part) => {
+            let mime_type = detect_mime_type(&part.upload.head);
+            if mime_type.is_none() {
+                errors.push(FieldError::new("file", "is not an image"));
             }
-        });
+            mime_type
+        }
+    };
+
+    match (tags, part, mime_type) {
+        (Some(tags), Some(part), Some(mime_type)) if errors.is_empty() => {
+            let new_image = NewImage {
+                tags,
+                mime_type,
+                file_name: part.file_name,
+                sha256: Some(part.upload.sha256),
+                size: Some(part.upload.size as i64),
+            };
+            Ok((new_image, part.upload.file))
+        }
+        _ => Err(errors),
     }
+}
 
-    let path_success = Path::new("./src/templates/upload.html");
+/// Body of a successful upload: where to follow thumbnail generation.
+#[derive(Debug, Serialize)]
+struct UploadAccepted {
+    image_id: i64,
+    job_id: i64,
+    status_url: String,
+    job_url: String,
+}
+
+/// An upload that went through.
+#[derive(Debug)]
+enum Accepted {
+    Created(UploadAccepted),
+    /// The content is stored already, as this image.
+    Duplicate(i64),
+}
+
+// Records the image, moves the staged original into storage and queues its
+// thumbnails; unless an image with the same content exists already.
+async fn accept_upload<T: ImageRepository + JobRepository + BlobStoreProvider>(
+    repo: Arc<T>,
+    new_image: &NewImage,
+    file: TempPath,
+) -> Result<Accepted> {
+    // Checksums are unique, so the insert itself tells whether the content is
+    // stored already, even when the same file is uploaded twice at once.
+    let image_id = match insert_image_into_db(repo.clone(), new_image).await {
+        Ok(image_id) => image_id,
+        Err(e) if is_unique_violation(&e) => {
+            let sha256 = new_image.sha256.as_deref().unwrap_or_default();
+            let image_id = find_duplicate(repo.as_ref(), sha256)
+                .await?
+                .context("the duplicate vanished after a conflicting insert")?;
+            return Ok(Accepted::Duplicate(image_id));
+        }
+        Err(e) => return Err(e),
+    };
+    println!("id is {}", image_id);
+
+    repo.blob_store()
+        .put_file(&original_key(image_id), file)
+        .await
+        .context("Failed to store image")?;
+    queue_thumbnails(&*repo, image_id)
+        .await
+        .map(Accepted::Created)
+}
+
+// Queues generation of all thumbnails of a new or replaced original.
+async fn queue_thumbnails<T: JobRepository>(repo: &T, image_id: i64) -> Result<UploadAccepted> {
+    // The uploader is likely to look at the thumbnails right away.
+    let job = NewJob {
+        image_id,
+        presets: None,
+        priority: Priority::Interactive,
+    };
+    let job_id = repo
+        .enqueue(&job)
+        .await
+        .context("Failed to queue thumbnails")?;
+    Ok(UploadAccepted {
+        image_id,
+        job_id,
+        status_url: image_status_url(image_id),
+        job_url: job_url(job_id),
+    })
+}
+
+// Whether reading a body failed on the `DefaultBodyLimit` of the route.
+fn exceeds_body_limit(error: &axum::Error) -> bool {
+    std::error::Error::source(error)
+        .is_some_and(|source| source.is::<http_body_util::LengthLimitError>())
+}
+
+// Browsers submitting the upload form get a page; API clients get JSON.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+async fn upload_failed(html: bool, status: StatusCode, message: &str) -> Response {
+    if !html {
+        return api_error(status, message);
+    }
     let path_error = Path::new("./src/templates/upload_error.html");
+    match read_to_string(&path_error).await {
+        Ok(content) => (status, Html(content)).into_response(),
+        Err(_) => (status, Html("Error page not found.")).into_response(),
+    }
+}
 
-    match read_to_string(&path_success).await {
-        Ok(content) => Html(content),
-        Err(_) => {
-            let content = read_to_string(&path_error).await.unwrap();
-            Html(content)
+// Sniffs the format from the data. The client's Content-Type is never
+// trusted: anything that isn't a format we can decode is not an image, so
+// nothing else is ever served inline under an `image/*` type.
+fn detect_mime_type(data: &[u8]) -> Option<String> {
+    image::guess_format(data)
+        .ok()
+        .filter(|format| format.reading_enabled())
+        .map(|format| format.to_mime_type().to_string())
+}
+
+/// How long the URLs listed by `GET /images` stay valid when URL signing is on.
+const LISTED_URL_TTL: u64 = 60 * 60;
+
+/// An entry of `GET /images`: the image and where to fetch it and its
+/// thumbnail, signed when URL signing is on.
+#[derive(Debug, Serialize)]
+struct ListedImage {
+    #[serde(flatten)]
+    image: Image,
+    url: String,
+    thumbnail_url: String,
+}
+
+impl ListedImage {
+    fn new(image: Image, config: &Config) -> Self {
+        let url = |path: String| match &config.url_signer {
+            Some(signer) => signer.sign_for(&path, LISTED_URL_TTL),
+            None => path,
+        };
+        Self {
+            url: url(format!("/images/{}", image.id)),
+            thumbnail_url: url(format!("/thumbnails/{}", image.id)),
+            image,
         }
     }
 }
 
-async fn show_images<T: ImageRepository>(State(repo): State<Arc<T>>) -> Json<Vec<Image>> {
+async fn show_images<T: ImageRepository + ConfigProvider>(
+    State(repo): State<Arc<T>>,
+) -> Json<Vec<ListedImage>> {
     let filter = ImageFilter {
         id: None,
         tags: None,
-        thumbnail: None,
+        thumbnail_status: None,
+        sha256: None,
     };
     let images: Result<ImageResult> = repo.filter(filter).await;
-    match images {
-        Ok(ImageResult::Single(single_result)) => Json(vec![single_result]),
-        Ok(ImageResult::Multiple(images)) => Json(images),
-        _ => Json(vec![]),
-    }
+    let images = match images {
+        Ok(ImageResult::Single(single_result)) => vec![single_result],
+        Ok(ImageResult::Multiple(images)) => images,
+        _ => vec![],
+    };
+    let config = repo.config();
+    Json(
+        images
+            .into_iter()
+            .map(|image| ListedImage::new(image, config))
+            .collect(),
+    )
 }
 
-async fn uploader_chunks(mut multipart: Multipart) -> impl IntoResponse {
-    let mut tags = None;
-    let mut image = None;
-
-    while let Ok(Some(mut field)) = multipart.next_field().await {
-        let name = field.name().unwrap_or_default().to_string();
+/// Body of `PATCH /images/:id`; absent fields are left unchanged.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImagePatch {
+    tags: Option<String>,
+    /// Name presented on download; an empty name clears it.
+    file_name: Option<String>,
+}
 
-        match name.as_str() {
-            "tags" => {
-                println!("got form field tags: {}", name);
-                let data = match field.bytes().await {
-                    Ok(data) => data,
-                    Err(_) => return Html("Error reading tags data".to_string()),
-                };
-                tags = Some(String::from_utf8(data.to_vec()).unwrap_or_default());
-            }
-            "file" => {
-                println!("got form field file: {}", name);
-                if let Some(file_name) = field.file_name().map(|n| n.to_string()) {
-                    let file_path = Path::new("../images/").join(&file_name);
-                    let mut file = OpenOptions::new()
-                        .write(true)
-                        .append(true)
-                        .create(true)
-                        .open(&file_path)
-                        .await
-                        .expect("sadad");
-
-                    let mut total_bytes = 0usize;
-                    while let Ok(Some(chunk)) = field.chunk().await {
-                        file.write_all(&chunk)
-                            .await
-                            .expect("Failed to write to file");
-                        let chunk_size = chunk.len();
-                        total_bytes += chunk_size;
-                        println!("Received {} bytes (total: {})", chunk_size, total_bytes);
-                    }
-                    println!("Finished file: {} ({} bytes)", file_name, total_bytes);
-                    image = Some(true);
-                } else {
-                    println!("shit happens, over and over again")
-                }
-            }
-            _ => return Html(format!("Unknown field: {}", name)),
+async fn patch_image<T: ImageRepository>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+    Json(patch): Json<ImagePatch>,
+) -> Response {
+    let mut image = match find_image(repo.as_ref(), id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return api_error(StatusCode::NOT_FOUND, "image not found"),
+        Err(e) => {
+            eprintln!("Failed to look up image {id}: {e}");
+            return api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up image");
         }
+    };
+    if let Some(tags) = patch.tags {
+        image.tags = tags;
     }
-    println!("Exited the loop");
-
-    match (tags, image) {
-        (Some(tags), Some(_)) => {
-            println!("Received tags: {} and image", tags);
-            Html("Ok".to_string())
+    if let Some(file_name) = patch.file_name {
+        image.file_name = Some(sanitize_file_name(&file_name)).filter(|name| !name.is_empty());
+    }
+    match repo.update(image.clone()).await {
+        Ok(()) => Json(image).into_response(),
+        Err(e) => {
+            eprintln!("Failed to update image {id}: {e}");
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to update image")
         }
-        _ => Html("Missing field".to_string()),
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum ContentType {
-    ImagePng,
-    ImageJpg,
-}
-
-impl Display for ContentType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ContentType::ImagePng => write!(f, "content type PNG"),
-            ContentType::ImageJpg => write!(f, "content type JPG"),
+async fn delete_image<T: ImageRepository + BlobStoreProvider>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+) -> Response {
+    match find_image(repo.as_ref(), id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return api_error(StatusCode::NOT_FOUND, "image not found"),
+        Err(e) => {
+            eprintln!("Failed to look up image {id}: {e}");
+            return api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up image");
+        }
+    }
+    match remove_image(&*repo, id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            eprintln!("Failed to delete image {id}: {e:#}");
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to delete image")
         }
     }
 }
 
-fn get_content_type(field: &dyn FieldBehavior) -> Option<ContentType> {
-    field
-        .content_type()
-        .map(|mime| mime.as_ref())
-        .and_then(|mime_str| match mime_str {
-            "image/png" => Some(ContentType::ImagePng),
-            "image/jpg" => Some(ContentType::ImageJpg),
-            _ => None,
-        })
+// Removes the row first: blobs left behind by a failure after that are
+// orphans, which garbage collection deletes.
+async fn remove_image<T: ImageRepository + BlobStoreProvider>(repo: &T, id: i64) -> Result<()> {
+    repo.delete(id).await?;
+    let store = repo.blob_store();
+    store.delete(&original_key(id)).await?;
+    delete_derived_blobs(store, id).await
 }
 
-trait FieldBehavior {
-    fn content_type(&self) -> Option<&str>;
-}
+// Replaces the original with the request body, streamed to a temporary file
+// like uploads. The file name comes from the request's `Content-Disposition`,
+// or else keeps the old name with the new format's extension. The cached
+// variants and tiles of the old content are dropped and the thumbnails
+// regenerated.
+async fn replace_image_file<
+    T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider,
+>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+    request: Request,
+) -> Response {
+    let mut image = match find_image(repo.as_ref(), id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return api_error(StatusCode::NOT_FOUND, "image not found"),
+        Err(e) => {
+            eprintln!("Failed to look up image {id}: {e}");
+            return api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up image");
+        }
+    };
+    let file_name = request
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(requested_file_name);
+    let config = repo.config();
+    let body = request.with_limited_body().into_body().into_data_stream();
+    let upload = match stage_upload(body, &config.upload_dir).await {
+        Ok(upload) => upload,
+        Err(StageError::Read(e)) if exceeds_body_limit(&e) => {
+            return UploadRejection::TooLarge(config.max_upload_size).into_response()
+        }
+        Err(StageError::Read(e)) => {
+            return UploadRejection::Malformed(format!("failed to read the body: {e}"))
+                .into_response()
+        }
+        Err(StageError::Write(e)) => {
+            eprintln!("Failed to stage upload: {e}");
+            return UploadRejection::Failed.into_response();
+        }
+    };
+    if upload.size == 0 {
+        return api_error(StatusCode::BAD_REQUEST, "the file is empty");
+    }
+    // Checked before anything is replaced, so the image keeps its thumbnails.
+    let Some(mime_type) = detect_mime_type(&upload.head) else {
+        return validation_failed(vec![FieldError::new("file", "is not an image")]);
+    };
+    let previous = image.clone();
+    image.mime_type = mime_type;
+    image.file_name = file_name.or_else(|| {
+        let old_name = previous.file_name.as_deref()?;
+        let stem = old_name.rsplit_once('.').map_or(old_name, |(stem, _)| stem);
+        Some(format!("{stem}.{}", image.extension()))
+    });
+    image.sha256 = Some(upload.sha256);
+    image.size = Some(upload.size as i64);
+    image.thumbnails_pending();
 
-impl FieldBehavior for Field<'_> {
-    fn content_type(&self) -> Option<&str> {
-        self.content_type().map(|mime| mime.as_ref())
+    // The row is updated first and restored if the file cannot be stored, so
+    // the stored bytes never disagree with the recorded type and checksum.
+    let replaced = async {
+        repo.update(image).await?;
+        let stored = repo
+            .blob_store()
+            .put_file(&original_key(id), upload.file)
+            .await;
+        if let Err(e) = stored {
+            if let Err(restore) = repo.update(previous).await {
+                eprintln!("Failed to restore image {id} after a failed replacement: {restore}");
+            }
+            return Err(e.context("Failed to store image"));
+        }
+        delete_derived_blobs(repo.blob_store(), id).await?;
+        queue_thumbnails(&*repo, id).await
+    };
+    match replaced.await {
+        Ok(accepted) => (StatusCode::ACCEPTED, Json(accepted)).into_response(),
+        Err(e) if is_unique_violation(&e) => {
+            api_error(StatusCode::CONFLICT, "another image has the same content")
+        }
+        Err(e) => {
+            eprintln!("Failed to replace the file of image {id}: {e:#}");
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the file could not be replaced",
+            )
+        }
     }
 }
 
@@ -341,9 +1296,19 @@ mod tests {
     use super::*;
 
     use async_trait::async_trait;
+    use axum::body::Bytes;
     use std::collections::HashMap;
     use std::sync::Mutex;
-    use tempfile::tempdir;
+
+    use tower::ServiceExt;
+
+    use crate::config::{AdminToken, Config};
+    use crate::repository::image_repository::ThumbnailStatus;
+    use crate::service::staged_upload::sha256_hex;
+    use crate::service::url_signer::UrlSigner;
+    use crate::storage::local::LocalBlobStore;
+    use crate::test_support::{self, app_state_with_store, new_image};
+    use crate::AppState;
 
     #[derive(Clone)]
     struct MockImageRepository {
@@ -364,7 +1329,7 @@ mod tests {
             self.data.lock().unwrap().len().to_string()
         }
 
-        async fn insert(&self, _tags: &str) -> Result<i64, anyhow::Error> {
+        async fn insert(&self, _image: &NewImage) -> Result<i64, anyhow::Error> {
             let mut data = self.data.lock().unwrap();
             let id = data.len() as i64 + 1;
             data.insert(id, create_image());
@@ -376,7 +1341,7 @@ mod tests {
             Ok(())
         }
 
-        async fn update(&self, image: Image) -> Result<()> {
+        async fn update(&self, _image: Image) -> Result<()> {
             todo!()
         }
         async fn filter(&self, _filter: ImageFilter) -> Result<ImageResult, anyhow::Error> {
@@ -388,29 +1353,575 @@ mod tests {
     fn create_image() -> Image {
         let id = 1;
         let tags = "tag1,tag2".to_string();
-        let thumbnail = true;
+        let thumbnail_status = ThumbnailStatus::Ready;
 
-        Image::new(id, tags.clone(), thumbnail)
+        Image::new(id, tags.clone(), thumbnail_status)
     }
 
     #[tokio::test]
     async fn test_new_image() {
         let id = 1;
         let tags = "tag1,tag2".to_string();
-        let thumbnail = true;
+        let thumbnail_status = ThumbnailStatus::Ready;
         let image = create_image();
 
         assert_eq!(image.id, id);
         assert_eq!(image.tags, tags);
-        assert_eq!(image.thumbnail, thumbnail);
+        assert_eq!(image.thumbnail_status, thumbnail_status);
+    }
+
+    #[test]
+    fn mime_type_is_sniffed_from_content() {
+        let png = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        assert_eq!(detect_mime_type(&png).as_deref(), Some("image/png"));
+        assert_eq!(detect_mime_type(b"????"), None);
+        assert_eq!(detect_mime_type(b"<svg onload=alert(1)>"), None);
     }
 
     #[tokio::test]
     async fn test_count_images() {
         let repository = Arc::new(MockImageRepository::new());
         let state = State(repository.clone());
-        repository.insert("").await.unwrap();
+        let image = new_image("image/jpeg");
+        repository.insert(&image).await.unwrap();
         let response = count_images(state).await;
         assert_eq!(response, "1");
     }
+
+    async fn app_state() -> Arc<AppState> {
+        let config = Config {
+            admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+            ..Config::default()
+        };
+        test_support::app_state(config).await
+    }
+
+    const ADMIN_TOKEN: &str = "secret";
+
+    // Sends a request as an operator, with the admin token of `app_state`.
+    async fn send(router: &Router, method: &str, uri: &str, body: Body) -> StatusCode {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .body(body)
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn only_operators_can_edit_replace_or_delete() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let new_image = NewImage {
+            tags: "kept".to_string(),
+            ..new_image("image/png")
+        };
+        let id = state.insert(&new_image).await.unwrap();
+        state
+            .blob_store()
+            .put(&original_key(id), Bytes::from_static(&PNG_SIGNATURE))
+            .await
+            .unwrap();
+
+        let uri = format!("/images/{id}");
+        let file_uri = format!("/images/{id}/file");
+        for (method, uri, body, token) in [
+            ("DELETE", &uri, "", None),
+            ("PATCH", &uri, r#"{"tags":"changed"}"#, None),
+            ("PUT", &file_uri, "", None),
+            ("DELETE", &uri, "", Some("wrong")),
+        ] {
+            let mut request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let request = request.body(Body::from(body)).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{method} {uri}"
+            );
+        }
+
+        let image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.tags, "kept");
+        assert!(state.blob_store().exists(&original_key(id)).await.unwrap());
+        let status = send(&router, "GET", &uri, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn replacements_are_renamed_for_their_format() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let new_image = NewImage {
+            file_name: Some("photo.jpg".to_string()),
+            ..new_image("image/jpeg")
+        };
+        let id = state.insert(&new_image).await.unwrap();
+        let uri = format!("/images/{id}/file");
+
+        let png = Body::from(&PNG_SIGNATURE[..]);
+        assert_eq!(send(&router, "PUT", &uri, png).await, StatusCode::ACCEPTED);
+        let image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.file_name.as_deref(), Some("photo.png"));
+
+        let request = axum::http::Request::builder()
+            .method("PUT")
+            .uri(&uri)
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .header(
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"scan.png\"",
+            )
+            .body(Body::from(&PNG_SIGNATURE[..]))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.file_name.as_deref(), Some("scan.png"));
+    }
+
+    #[tokio::test]
+    async fn failed_replacements_leave_the_row_alone() {
+        // A storage root that is a file, so nothing can be stored below it.
+        let root = tempfile::NamedTempFile::new().unwrap();
+        let config = Config {
+            admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+            ..Config::default()
+        };
+        let store = Arc::new(LocalBlobStore::new(root.path()));
+        let state = app_state_with_store(config, store).await;
+        let router = image_routes(state.clone());
+        let new_image = NewImage {
+            file_name: Some("photo.jpg".to_string()),
+            ..new_image("image/jpeg")
+        };
+        let id = state.insert(&new_image).await.unwrap();
+        let before = find_image(&*state, id).await.unwrap().unwrap();
+
+        let png = Body::from(&PNG_SIGNATURE[..]);
+        let status = send(&router, "PUT", &format!("/images/{id}/file"), png).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(find_image(&*state, id).await.unwrap().unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn images_can_be_edited_replaced_and_deleted() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let new_image = NewImage {
+            tags: "old".to_string(),
+            file_name: Some("old.jpg".to_string()),
+            ..new_image("image/jpeg")
+        };
+        let id = state.insert(&new_image).await.unwrap();
+        let store = state.blob_store();
+        store.put(&original_key(id), Bytes::new()).await.unwrap();
+        store
+            .put(&format!("{id}_thumbnail.jpg"), Bytes::new())
+            .await
+            .unwrap();
+
+        let patch = Body::from(r#"{"tags": "new", "file_name": ""}"#);
+        let status = send(&router, "PATCH", &format!("/images/{id}"), patch).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.tags, "new");
+        assert_eq!(image.file_name, None);
+
+        let unknown = Body::from(r#"{"thumbnail_status": "ready"}"#);
+        let status = send(&router, "PATCH", &format!("/images/{id}"), unknown).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        image.thumbnails_ready(1);
+        state.update(image).await.unwrap();
+        let text = Body::from("not an image");
+        let status = send(&router, "PUT", &format!("/images/{id}/file"), text).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.thumbnail_status, ThumbnailStatus::Ready);
+        assert!(store.exists(&format!("{id}_thumbnail.jpg")).await.unwrap());
+
+        let png = Body::from(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'][..]);
+        let status = send(&router, "PUT", &format!("/images/{id}/file"), png).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let image = find_image(&*state, id).await.unwrap().unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(image.size, Some(PNG_SIGNATURE.len() as i64));
+        assert_eq!(image.sha256, Some(sha256_hex(&PNG_SIGNATURE)));
+        assert_eq!(image.thumbnail_status, ThumbnailStatus::Pending);
+        assert_eq!(store.list("").await.unwrap(), vec![original_key(id)]);
+        assert_eq!(state.images_with_pending_jobs().await.unwrap(), vec![id]);
+
+        let status = send(&router, "DELETE", &format!("/images/{id}"), Body::empty()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(find_image(&*state, id).await.unwrap().is_none());
+        assert!(store.list("").await.unwrap().is_empty());
+        let status = send(&router, "DELETE", &format!("/images/{id}"), Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+    // A `multipart/form-data` request to `uri` with the given (name, file name,
+    // content) parts.
+    fn upload_request(
+        uri: &str,
+        parts: &[(&str, Option<&str>, &[u8])],
+    ) -> axum::http::Request<Body> {
+        let mut body = Vec::new();
+        for (name, file_name, content) in parts {
+            body.extend_from_slice(b"--BOUNDARY\r\n");
+            let disposition = match file_name {
+                Some(file_name) => format!("form-data; name=\"{name}\"; filename=\"{file_name}\""),
+                None => format!("form-data; name=\"{name}\""),
+            };
+            body.extend_from_slice(
+                format!("Content-Disposition: {disposition}\r\n\r\n").as_bytes(),
+            );
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--BOUNDARY--\r\n");
+        axum::http::Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=BOUNDARY",
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn json_response(
+        router: &Router,
+        request: axum::http::Request<Body>,
+    ) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn api_uploads_return_the_created_image() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+
+        let request = upload_request(
+            "/api/images",
+            &[
+                ("tags", None, b"cats"),
+                ("file", Some("cat.png"), &PNG_SIGNATURE),
+            ],
+        );
+        let (status, headers, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = body["id"].as_i64().unwrap();
+        assert_eq!(headers[header::LOCATION], format!("/images/{id}"));
+        assert_eq!(body["tags"], "cats");
+        assert_eq!(body["mime_type"], "image/png");
+        assert_eq!(body["file_name"], "cat.png");
+        assert_eq!(body["thumbnail_status"], "pending");
+        assert_eq!(body["status_url"], format!("/images/{id}/status"));
+        assert_eq!(body["size"], PNG_SIGNATURE.len());
+        assert_eq!(body["sha256"], sha256_hex(&PNG_SIGNATURE));
+        assert!(state.blob_store().exists(&original_key(id)).await.unwrap());
+
+        // The original's ETag comes from the hash recorded at upload.
+        let request = axum::http::Request::get(format!("/images/{id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[header::ETAG],
+            format!("\"{}\"", &sha256_hex(&PNG_SIGNATURE)[..32])
+        );
+    }
+
+    #[tokio::test]
+    async fn replacements_may_not_duplicate_another_image() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let mut ids = Vec::new();
+        for content in [
+            PNG_SIGNATURE.to_vec(),
+            [PNG_SIGNATURE.as_slice(), b"b"].concat(),
+        ] {
+            let new_image = NewImage {
+                sha256: Some(sha256_hex(&content)),
+                size: Some(content.len() as i64),
+                ..new_image("image/png")
+            };
+            let id = state.insert(&new_image).await.unwrap();
+            let store = state.blob_store();
+            store.put(&original_key(id), content.into()).await.unwrap();
+            ids.push(id);
+        }
+
+        let png = Body::from(&PNG_SIGNATURE[..]);
+        let status = send(&router, "PUT", &format!("/images/{}/file", ids[1]), png).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let image = find_image(&*state, ids[1]).await.unwrap().unwrap();
+        assert_eq!(image.size, Some(PNG_SIGNATURE.len() as i64 + 1));
+
+        // An image may be replaced by its own content.
+        let png = Body::from(&PNG_SIGNATURE[..]);
+        let status = send(&router, "PUT", &format!("/images/{}/file", ids[0]), png).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn batch_uploads_report_each_file() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let bird_png = [PNG_SIGNATURE.as_slice(), b"bird"].concat();
+        let dog_png = [PNG_SIGNATURE.as_slice(), b"dog"].concat();
+
+        // Each file's tags come right before it, as browsers send them.
+        let request = upload_request(
+            "/images/upload",
+            &[
+                ("tags", None, b"shared"),
+                ("tags[0]", None, b"cat"),
+                ("file", Some("cat.png"), &PNG_SIGNATURE),
+                ("tags[1]", None, b"copy"),
+                ("file", Some("copy.png"), &PNG_SIGNATURE),
+                ("file", Some("notes.txt"), b"hello"),
+                ("tags[3]", None, b"dog"),
+                ("file", Some("dog.png"), &dog_png),
+                ("file", Some("bird.png"), &bird_png),
+            ],
+        );
+        let (status, _, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let results = body["results"].as_array().unwrap();
+        let statuses: Vec<&str> = results
+            .iter()
+            .map(|result| result["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            ["created", "duplicate", "rejected", "created", "created"]
+        );
+
+        let tags_of = |result: &serde_json::Value| {
+            let id = result["image_id"].as_i64().unwrap();
+            let state = state.clone();
+            async move { find_image(&*state, id).await.unwrap().unwrap().tags }
+        };
+        assert_eq!(tags_of(&results[0]).await, "cat");
+        assert_eq!(results[1]["file_name"], "copy.png");
+        assert_eq!(results[1]["image_id"], results[0]["image_id"]);
+        assert_eq!(results[2]["reason"], "`file` is not an image");
+        assert_eq!(tags_of(&results[3]).await, "dog");
+        assert_eq!(tags_of(&results[4]).await, "shared");
+        assert_eq!(state.count().await, "3 images in the database");
+
+        // Single uploads are checked for duplicates as well.
+        let single = |uri| {
+            upload_request(
+                uri,
+                &[
+                    ("tags", None, b"again"),
+                    ("file", Some("dog.png"), &dog_png),
+                ],
+            )
+        };
+        let (status, _, body) = json_response(&router, single("/images/upload")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "duplicate");
+        assert_eq!(body["image_id"], results[3]["image_id"]);
+        let (status, headers, body) = json_response(&router, single("/api/images")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["id"], results[3]["image_id"]);
+        assert_eq!(headers[header::LOCATION], format!("/images/{}", body["id"]));
+        assert_eq!(state.count().await, "3 images in the database");
+
+        // Browsers get the results as a page.
+        let mut request = upload_request(
+            "/images/upload",
+            &[
+                ("tags", None, b"page"),
+                ("file", Some("<copy>.png"), &PNG_SIGNATURE),
+                ("file", Some("notes.txt"), b"hello"),
+            ],
+        );
+        request
+            .headers_mut()
+            .insert(header::ACCEPT, "text/html".parse().unwrap());
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("&lt;copy&gt;.png: already uploaded as image"));
+        assert!(page.contains("notes.txt: rejected: `file` is not an image"));
+    }
+
+    #[tokio::test]
+    async fn head_requests_describe_the_original_without_a_body() {
+        let state = app_state().await;
+        let router = image_routes(state.clone());
+        let new_image = new_image("image/png");
+        let id = state.insert(&new_image).await.unwrap();
+        let original = Bytes::from_static(&PNG_SIGNATURE);
+        state
+            .blob_store()
+            .put(&original_key(id), original)
+            .await
+            .unwrap();
+
+        let request = axum::http::Request::head(format!("/images/{id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::CONTENT_LENGTH],
+            PNG_SIGNATURE.len().to_string()
+        );
+        assert!(headers[header::ETAG].to_str().unwrap().starts_with('"'));
+        assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn listed_urls_are_signed_when_signing_is_on() {
+        let config = Config {
+            url_signer: Some(UrlSigner::new("secret")),
+            ..Config::default()
+        };
+        let state = test_support::app_state(config).await;
+        let router = image_routes(state.clone());
+        let new_image = new_image("image/png");
+        let id = state.insert(&new_image).await.unwrap();
+        let original = Bytes::from_static(&PNG_SIGNATURE);
+        state
+            .blob_store()
+            .put(&original_key(id), original)
+            .await
+            .unwrap();
+
+        let request = axum::http::Request::get("/images")
+            .body(Body::empty())
+            .unwrap();
+        let (_, _, body) = json_response(&router, request).await;
+        let url = body[0]["url"].as_str().unwrap();
+        assert!(url.starts_with(&format!("/images/{id}?exp=")));
+        assert!(body[0]["thumbnail_url"].as_str().unwrap().contains("&sig="));
+
+        let status = send(&router, "GET", url, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let unsigned = format!("/images/{id}");
+        let status = send(&router, "GET", &unsigned, Body::empty()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Without a key, the plain paths are listed.
+        let image = Image::new(7, String::new(), ThumbnailStatus::Ready);
+        let listed = ListedImage::new(image, &Config::default());
+        assert_eq!(listed.url, "/images/7");
+        assert_eq!(listed.thumbnail_url, "/thumbnails/7");
+    }
+
+    #[tokio::test]
+    async fn uploads_over_the_limit_are_rejected() {
+        let config = Config {
+            max_upload_size: 1024,
+            admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+            ..Config::default()
+        };
+        let state = test_support::app_state(config).await;
+        let router = image_routes(state.clone());
+
+        let large = [PNG_SIGNATURE.as_slice(), &[0; 2048]].concat();
+        let request = upload_request(
+            "/api/images",
+            &[("tags", None, b"big"), ("file", Some("big.png"), &large)],
+        );
+        let (status, _, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "the upload exceeds the limit of 1024 bytes");
+        assert_eq!(state.count().await, "0 images in the database");
+
+        let new_image = new_image("image/png");
+        let id = state.insert(&new_image).await.unwrap();
+        let uri = format!("/images/{id}/file");
+        let status = send(&router, "PUT", &uri, Body::from(large)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!state.blob_store().exists(&original_key(id)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn declared_image_types_are_not_trusted() {
+        let router = image_routes(app_state().await);
+        let body = "--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"tags\"\r\n\r\nx\r\n\
+            --BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.svg\"\r\n\
+            Content-Type: image/svg+xml\r\n\r\n\
+            <svg xmlns=\"http://www.w3.org/2000/svg\" onload=\"alert(1)\"/>\r\n\
+            --BOUNDARY--\r\n";
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/api/images")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=BOUNDARY",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let (status, _, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body["fields"],
+            serde_json::json!([{"field": "file", "message": "is not an image"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_api_uploads_list_every_problem() {
+        let router = image_routes(app_state().await);
+
+        let request = upload_request("/api/images", &[("file", Some("notes.txt"), b"hello")]);
+        let (status, _, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body["fields"],
+            serde_json::json!([
+                {"field": "tags", "message": "is required"},
+                {"field": "file", "message": "is not an image"},
+            ])
+        );
+
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/api/images")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let (status, _, body) = json_response(&router, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("malformed upload"));
+    }
 }
@@ -0,0 +1,210 @@
+// Progress of background work, so clients can tell when thumbnails are ready.
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::repository::image_repository::{Image, ImageRepository, ThumbnailStatus};
+use crate::repository::job_repository::{Job, JobRepository, JobState};
+use crate::routes::api_error::api_error;
+use crate::routes::image_routes::find_image;
+
+/// Progress as reported to clients, for jobs and for images alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Progress {
+    Queued,
+    Processing,
+    Ready,
+    Failed,
+}
+
+impl From<JobState> for Progress {
+    fn from(state: JobState) -> Self {
+        match state {
+            JobState::Queued => Progress::Queued,
+            JobState::Running => Progress::Processing,
+            JobState::Done => Progress::Ready,
+            JobState::Failed => Progress::Failed,
+        }
+    }
+}
+
+/// Body of `GET /jobs/:id`. Timestamps are Unix seconds.
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: i64,
+    pub image_id: i64,
+    pub status: Progress,
+    pub attempts: u32,
+    /// Error of the latest failed run; kept while the job is retried.
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub updated_at: i64,
+    /// When the job reached `ready` or `failed`.
+    pub finished_at: Option<i64>,
+    pub url: String,
+}
+
+impl From<Job> for JobStatus {
+    fn from(job: Job) -> Self {
+        let status = Progress::from(job.state);
+        let finished = matches!(status, Progress::Ready | Progress::Failed);
+        Self {
+            url: job_url(job.id),
+            id: job.id,
+            image_id: job.image_id,
+            status,
+            attempts: job.attempts,
+            error: job.last_error,
+            created_at: job.created_at,
+            started_at: job.started_at,
+            updated_at: job.updated_at,
+            finished_at: finished.then_some(job.updated_at),
+        }
+    }
+}
+
+/// Body of `GET /images/:id/status`.
+#[derive(Debug, Serialize)]
+pub struct ImageStatus {
+    pub image_id: i64,
+    pub status: Progress,
+    pub error: Option<String>,
+    /// Generator version of the current thumbnails, once they are ready.
+    pub thumbnail_version: Option<u32>,
+    /// The most recent job of the image, if it ever had one.
+    pub job: Option<JobStatus>,
+}
+
+impl ImageStatus {
+    fn new(image: Image, job: Option<Job>) -> Self {
+        let job = job.map(JobStatus::from);
+        let status = match image.thumbnail_status {
+            ThumbnailStatus::Ready => Progress::Ready,
+            ThumbnailStatus::Failed => Progress::Failed,
+            // Pending images wait for their job; one without a job is picked
+            // up on the next start.
+            ThumbnailStatus::Pending => match job.as_ref().map(|job| job.status) {
+                Some(Progress::Processing) => Progress::Processing,
+                Some(Progress::Failed) => Progress::Failed,
+                _ => Progress::Queued,
+            },
+        };
+        Self {
+            image_id: image.id,
+            status,
+            error: image.thumbnail_error,
+            thumbnail_version: image.thumbnail_version,
+            job,
+        }
+    }
+}
+
+pub(crate) fn job_url(id: i64) -> String {
+    format!("/jobs/{id}")
+}
+
+pub(crate) fn image_status_url(id: i64) -> String {
+    format!("/images/{id}/status")
+}
+
+pub fn job_routes<T: ImageRepository + JobRepository>(repository: Arc<T>) -> Router {
+    Router::new()
+        .route("/jobs/:id", get(get_job::<T>))
+        .route("/images/:id/status", get(get_image_status::<T>))
+        .with_state(repository)
+}
+
+async fn get_job<T: JobRepository>(State(repo): State<Arc<T>>, Path(id): Path<i64>) -> Response {
+    match repo.job(id).await {
+        Ok(Some(job)) => Json(JobStatus::from(job)).into_response(),
+        Ok(None) => api_error(StatusCode::NOT_FOUND, "job not found"),
+        Err(e) => {
+            eprintln!("Failed to look up job {id}: {e}");
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up job")
+        }
+    }
+}
+
+async fn get_image_status<T: ImageRepository + JobRepository>(
+    State(repo): State<Arc<T>>,
+    Path(id): Path<i64>,
+) -> Response {
+    let image = match find_image(repo.as_ref(), id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return api_error(StatusCode::NOT_FOUND, "image not found"),
+        Err(e) => {
+            eprintln!("Failed to look up image {id}: {e}");
+            return api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up image");
+        }
+    };
+    match repo.latest_job_for_image(id).await {
+        Ok(job) => Json(ImageStatus::new(image, job)).into_response(),
+        Err(e) => {
+            eprintln!("Failed to look up jobs of image {id}: {e}");
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to look up image")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::service::image_executor::Priority;
+
+    fn job(state: JobState) -> Job {
+        Job {
+            id: 3,
+            image_id: 7,
+            presets: None,
+            state,
+            priority: Priority::Background,
+            attempts: 1,
+            last_error: None,
+            created_at: 100,
+            started_at: Some(110),
+            updated_at: 120,
+        }
+    }
+
+    #[test]
+    fn finished_jobs_report_when_they_finished() {
+        let running = JobStatus::from(job(JobState::Running));
+        assert_eq!(running.status, Progress::Processing);
+        assert_eq!(running.finished_at, None);
+
+        let done = JobStatus::from(job(JobState::Done));
+        assert_eq!(done.status, Progress::Ready);
+        assert_eq!(done.finished_at, Some(120));
+        assert_eq!(done.url, "/jobs/3");
+    }
+
+    #[test]
+    fn pending_images_follow_their_job() {
+        let image = Image::new(7, String::new(), ThumbnailStatus::Pending);
+
+        let status = ImageStatus::new(image.clone(), Some(job(JobState::Running)));
+        assert_eq!(status.status, Progress::Processing);
+        assert_eq!(
+            ImageStatus::new(image.clone(), None).status,
+            Progress::Queued
+        );
+
+        let mut ready = image;
+        ready.thumbnails_ready(1);
+        // A later job regenerating single presets does not hide the thumbnails.
+        let status = ImageStatus::new(ready, Some(job(JobState::Queued)));
+        assert_eq!(status.status, Progress::Ready);
+        assert_eq!(
+            serde_json::to_value(&status).unwrap()["job"]["status"],
+            "queued"
+        );
+    }
+}
@@ -1 +1,10 @@
-pub mod image_routes;
\ No newline at end of file
+pub mod admin_routes;
+pub mod api_error;
+pub mod byte_ranges;
+pub mod contact_sheet_routes;
+pub mod content_disposition;
+pub mod http_cache;
+pub mod image_routes;
+pub mod job_routes;
+pub mod signed_urls;
+pub mod tile_routes;
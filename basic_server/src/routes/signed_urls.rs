@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::ConfigProvider;
+use crate::service::url_signer::SignatureError;
+
+/// Rejects requests without a valid `sig` when URL signing is configured
+/// (`URL_SIGNING_KEY`); passes everything through otherwise.
+pub async fn require_signature<T: ConfigProvider>(
+    State(repo): State<Arc<T>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(signer) = &repo.config().url_signer else {
+        return next.run(request).await;
+    };
+
+    match signer.verify(request.uri().path(), request.uri().query()) {
+        Ok(()) => next.run(request).await,
+        Err(SignatureError::Missing) => {
+            (StatusCode::FORBIDDEN, "signature required").into_response()
+        }
+        Err(SignatureError::Invalid) => {
+            (StatusCode::FORBIDDEN, "invalid signature").into_response()
+        }
+        Err(SignatureError::Expired) => {
+            (StatusCode::FORBIDDEN, "signature expired").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    use crate::config::Config;
+    use crate::service::url_signer::UrlSigner;
+
+    struct TestState(Config);
+
+    impl ConfigProvider for TestState {
+        fn config(&self) -> &Config {
+            &self.0
+        }
+    }
+
+    fn router(url_signer: Option<UrlSigner>) -> Router {
+        let state = Arc::new(TestState(Config {
+            url_signer,
+            ..Config::default()
+        }));
+        Router::new()
+            .route("/images/:id", get(|| async { "image" }))
+            .route_layer(middleware::from_fn_with_state(
+                state,
+                require_signature::<TestState>,
+            ))
+    }
+
+    async fn status(router: Router, uri: &str) -> StatusCode {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn unsigned_requests_pass_when_signing_is_off() {
+        assert_eq!(status(router(None), "/images/1").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn signatures_are_enforced_when_configured() {
+        let signer = UrlSigner::new("secret");
+        let signed = signer.sign("/images/1", None);
+
+        assert_eq!(
+            status(router(Some(signer.clone())), "/images/1").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(router(Some(signer)), &signed).await, StatusCode::OK);
+    }
+}
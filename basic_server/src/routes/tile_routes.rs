@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use anyhow::Result;
+use axum::body::Body;
+use axum::extract::{Path as Path2, Query, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use thumbnail::tiles::{render_tile_pyramid, TileOptions};
+use thumbnail::ThumbnailFormat;
+
+use crate::config::ConfigProvider;
+use crate::routes::content_disposition::content_disposition;
+use crate::routes::image_routes::{not_found, serve_blob, MediaQuery};
+use crate::routes::signed_urls::require_signature;
+use crate::service::image_executor::{ImageExecutorProvider, Priority};
+use crate::storage::{original_key, BlobStoreProvider};
+
+pub fn tile_routes<T: ConfigProvider + BlobStoreProvider + ImageExecutorProvider>(
+    state: Arc<T>,
+) -> Router {
+    Router::new()
+        .route("/images/:id/tiles.dzi", get(get_tile_descriptor::<T>))
+        .route("/images/:id/tiles/:level/:tile", get(get_tile::<T>))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_signature::<T>,
+        ))
+        .with_state(state)
+}
+
+// The whole pyramid of a format is cut from the original on the first miss
+// and cached next to it as `{id}_files/{level}/{col}_{row}.{ext}`, the Deep
+// Zoom directory layout, with its descriptor as `{id}_files/{ext}.dzi`.
+fn tile_key(id: i64, level: u32, col: u32, row: u32, format: ThumbnailFormat) -> String {
+    format!("{id}_files/{level}/{col}_{row}.{}", format.extension())
+}
+
+fn descriptor_key(id: i64, format: ThumbnailFormat) -> String {
+    format!("{id}_files/{}.dzi", format.extension())
+}
+
+async fn get_tile_descriptor<T: BlobStoreProvider + ImageExecutorProvider>(
+    State(repo): State<Arc<T>>,
+    Path2(id): Path2<i64>,
+) -> Response<Body> {
+    let options = TileOptions::default();
+    let key = descriptor_key(id, options.format);
+    let descriptor = match ensure_pyramid(&*repo, id, options).await {
+        Ok(true) => repo.blob_store().get_bytes(&key).await,
+        Ok(false) => return not_found().await,
+        Err(e) => Err(e),
+    };
+    match descriptor {
+        Ok(Some(descriptor)) => {
+            ([(header::CONTENT_TYPE, "application/xml")], descriptor).into_response()
+        }
+        Ok(None) => not_found().await,
+        Err(e) => {
+            eprintln!("Failed to create tiles of image {id}: {e}");
+            not_found().await
+        }
+    }
+}
+
+async fn get_tile<T: ConfigProvider + BlobStoreProvider + ImageExecutorProvider>(
+    State(repo): State<Arc<T>>,
+    Path2((id, level, tile)): Path2<(i64, u32, String)>,
+    Query(media): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let Some((col, row, format)) = parse_tile_name(&tile) else {
+        return not_found().await;
+    };
+    let options = TileOptions {
+        format,
+        ..TileOptions::default()
+    };
+
+    let store = repo.blob_store();
+    let key = tile_key(id, level, col, row, format);
+    // Tiles outside the pyramid are simply not found in the store.
+    match ensure_pyramid(&*repo, id, options).await {
+        Ok(true) => {
+            let tile_name = format!("{col}_{row}.{}", format.extension());
+            let disposition = content_disposition(media.disposition(), &tile_name);
+            let caching = media.caching(&repo.config().cache_control_variants);
+            serve_blob(
+                store,
+                &key,
+                None,
+                disposition,
+                format.mime_type(),
+                &headers,
+                caching,
+            )
+            .await
+        }
+        Ok(false) => not_found().await,
+        Err(e) => {
+            eprintln!("Failed to create tile {level}/{tile} for image {id}: {e}");
+            not_found().await
+        }
+    }
+}
+
+// Pyramids being cut, so the many tiles a viewer asks for at once wait for a
+// single build instead of each decoding the original.
+fn pyramid_lock(key: &str) -> Arc<tokio::sync::Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(tokio::sync::Mutex::new(()));
+    locks.insert(key.to_string(), Arc::downgrade(&lock));
+    lock
+}
+
+// Makes sure the pyramid of `options` is stored, cutting all of it from the
+// original on the first request. Returns false when the image is gone.
+async fn ensure_pyramid<T: BlobStoreProvider + ImageExecutorProvider>(
+    repo: &T,
+    id: i64,
+    options: TileOptions,
+) -> Result<bool> {
+    let store = repo.blob_store();
+    // The descriptor is stored last, so the pyramid is complete once it exists.
+    let descriptor = descriptor_key(id, options.format);
+    if store.exists(&descriptor).await? {
+        return Ok(true);
+    }
+    let lock = pyramid_lock(&descriptor);
+    let _building = lock.lock().await;
+    if store.exists(&descriptor).await? {
+        return Ok(true);
+    }
+
+    let Some(original) = store.get_bytes(&original_key(id)).await? else {
+        return Ok(false);
+    };
+    let (pyramid, tiles) = repo
+        .image_executor()
+        .run(Priority::Interactive, move || {
+            let mut tiles = Vec::new();
+            let pyramid = render_tile_pyramid(&original, options, |level, col, row, data| {
+                tiles.push((level, col, row, data));
+                Ok(())
+            })?;
+            anyhow::Ok((pyramid, tiles))
+        })
+        .await??;
+    for (level, col, row, data) in tiles {
+        let key = tile_key(id, level, col, row, options.format);
+        store.put(&key, data.into()).await?;
+    }
+    store
+        .put(&descriptor, pyramid.dzi_descriptor().into())
+        .await?;
+    Ok(true)
+}
+
+// Splits a tile file name such as `3_7.jpg` into column, row and format.
+fn parse_tile_name(tile: &str) -> Option<(u32, u32, ThumbnailFormat)> {
+    let (coordinates, extension) = tile.rsplit_once('.')?;
+    let (col, row) = coordinates.split_once('_')?;
+    Some((
+        col.parse().ok()?,
+        row.parse().ok()?,
+        ThumbnailFormat::from_extension(extension)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Bytes;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use crate::config::Config;
+    use crate::test_support::app_state;
+
+    async fn get(router: &Router, uri: &str) -> (StatusCode, Bytes) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn the_pyramid_is_cut_once_on_the_first_miss() {
+        let state = app_state(Config::default()).await;
+        let mut original = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(600, 300)
+            .write_to(&mut original, image::ImageFormat::Png)
+            .unwrap();
+        let store = state.blob_store();
+        store
+            .put(&original_key(7), original.into_inner().into())
+            .await
+            .unwrap();
+        let router = tile_routes(state.clone());
+
+        let (status, _) = get(&router, "/images/7/tiles/9/1_0.jpg").await;
+        assert_eq!(status, StatusCode::OK);
+        // Levels 0-8 of a single tile, 2x1 at level 9, 3x2 at level 10 and
+        // the descriptor.
+        assert_eq!(store.list("7_files/").await.unwrap().len(), 18);
+
+        // Everything else is served from the stored pyramid.
+        store.delete(&original_key(7)).await.unwrap();
+        let (status, descriptor) = get(&router, "/images/7/tiles.dzi").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(std::str::from_utf8(&descriptor)
+            .unwrap()
+            .contains(r#"<Size Width="600" Height="300"/>"#));
+        let (status, _) = get(&router, "/images/7/tiles/0/0_0.jpg").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = get(&router, "/images/7/tiles/10/3_0.jpg").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(&router, "/images/7/tiles/0/0_0.png").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn parses_tile_names() {
+        assert_eq!(
+            parse_tile_name("3_7.jpg"),
+            Some((3, 7, ThumbnailFormat::Jpeg))
+        );
+        assert_eq!(
+            parse_tile_name("0_0.webp"),
+            Some((0, 0, ThumbnailFormat::WebP))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tile_names() {
+        assert_eq!(parse_tile_name("3-7.jpg"), None);
+        assert_eq!(parse_tile_name("3_7"), None);
+        assert_eq!(parse_tile_name("a_7.jpg"), None);
+        assert_eq!(parse_tile_name("3_7.tiff"), None);
+    }
+}
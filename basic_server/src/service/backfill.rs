@@ -0,0 +1,230 @@
+// Keeps the image table and the blob store in step while the server runs:
+// queues thumbnails that are missing or outdated, drops rows whose original
+// is gone and deletes the blobs of images that no longer have a row.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use tokio::time::sleep;
+
+use crate::config::ConfigProvider;
+use crate::repository::image_repository::{Image, ImageFilter, ImageRepository, ImageResult};
+use crate::repository::job_repository::{unix_now, JobRepository, NewJob};
+use crate::routes::image_routes::delete_derived_blobs;
+use crate::service::gc::{classify, settled, Garbage};
+use crate::service::image_executor::Priority;
+use crate::storage::{original_key, BlobStoreProvider};
+
+/// Rows and blobs younger than this may belong to an upload that is still
+/// being stored, and are left alone.
+pub const RECONCILE_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// What a reconciliation found, and did unless it was a dry run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Images queued for thumbnail generation.
+    pub queued: Vec<i64>,
+    /// Images whose original is missing; their rows and variants are removed.
+    pub missing_originals: Vec<i64>,
+    /// Blobs of images that have no row.
+    pub orphaned_blobs: Vec<String>,
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+            && self.missing_originals.is_empty()
+            && self.orphaned_blobs.is_empty()
+    }
+
+    /// Human readable summary, one finding per line.
+    pub fn lines(&self, dry_run: bool) -> Vec<String> {
+        let (queue, remove, delete) = if dry_run {
+            ("would queue", "would remove", "would delete")
+        } else {
+            ("queued", "removed", "deleted")
+        };
+        let ids = |ids: &[i64]| {
+            ids.iter()
+                .map(i64::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut lines = Vec::new();
+        if !self.queued.is_empty() {
+            lines.push(format!(
+                "{queue} thumbnails for {} images: {}",
+                self.queued.len(),
+                ids(&self.queued)
+            ));
+        }
+        if !self.missing_originals.is_empty() {
+            lines.push(format!(
+                "{remove} {} images without an original: {}",
+                self.missing_originals.len(),
+                ids(&self.missing_originals)
+            ));
+        }
+        if !self.orphaned_blobs.is_empty() {
+            lines.push(format!(
+                "{delete} {} blobs of images without a row:",
+                self.orphaned_blobs.len()
+            ));
+            lines.extend(self.orphaned_blobs.iter().map(|key| format!("  {key}")));
+        }
+        lines
+    }
+}
+
+/// Reconciles now and then every `config.backfill_interval`, in the
+/// background so the server starts serving right away.
+pub fn spawn_backfill<T>(repo: Arc<T>)
+where
+    T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider,
+{
+    tokio::spawn(async move {
+        loop {
+            match reconcile(&*repo, RECONCILE_GRACE, false).await {
+                Ok(report) if report.is_empty() => println!("nothing to update"),
+                Ok(report) => report
+                    .lines(false)
+                    .iter()
+                    .for_each(|line| println!("Backfill: {line}")),
+                Err(e) => eprintln!("Backfill failed: {e:#}"),
+            }
+            sleep(repo.config().backfill_interval).await;
+        }
+    });
+}
+
+/// Compares the image table with the blob store. Unless `dry_run`, queues
+/// thumbnails for images that need them and have no pending job, removes
+/// images whose original is missing and deletes blobs of images without a
+/// row. Rows and blobs younger than `grace` are skipped.
+pub async fn reconcile<T>(repo: &T, grace: Duration, dry_run: bool) -> Result<ReconcileReport>
+where
+    T: ImageRepository + JobRepository + ConfigProvider + BlobStoreProvider + ?Sized,
+{
+    let image_filter = ImageFilter {
+        id: None,
+        tags: None,
+        thumbnail_status: None,
+        sha256: None,
+    };
+    let images: HashMap<i64, Image> = match repo.filter(image_filter).await? {
+        ImageResult::Multiple(images) => images,
+        ImageResult::Single(image) => vec![image],
+    }
+    .into_iter()
+    .map(|image| (image.id, image))
+    .collect();
+    let pending = repo.images_with_pending_jobs().await?;
+
+    let store = repo.blob_store();
+    let keys: BTreeSet<String> = store.list("").await?.into_iter().collect();
+    let settled_before = unix_now() - grace.as_secs() as i64;
+    let modified_before = SystemTime::now() - grace;
+
+    let mut report = ReconcileReport::default();
+    let mut ids: Vec<i64> = images.keys().copied().collect();
+    ids.sort();
+    for id in ids {
+        let image = &images[&id];
+        if !keys.contains(&original_key(id)) {
+            // Older images have no upload time and are settled by definition.
+            if image.created_at.is_none_or(|at| at <= settled_before) {
+                report.missing_originals.push(id);
+            }
+        } else if image.needs_thumbnails() && !pending.contains(&id) {
+            report.queued.push(id);
+        }
+    }
+    // Stale variants are left to `collect_garbage`, which runs on demand.
+    let image_ids: HashSet<i64> = images.keys().copied().collect();
+    for key in &keys {
+        let orphaned = classify(key, &image_ids, repo.config()) == Some(Garbage::Orphaned);
+        if orphaned && settled(store, key, modified_before).await? {
+            report.orphaned_blobs.push(key.clone());
+        }
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+    for &image_id in &report.queued {
+        let job = NewJob {
+            image_id,
+            presets: None,
+            priority: Priority::Background,
+        };
+        repo.enqueue(&job).await?;
+    }
+    for &id in &report.missing_originals {
+        repo.delete(id).await?;
+        delete_derived_blobs(store, id).await?;
+    }
+    for key in &report.orphaned_blobs {
+        store.delete(key).await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Bytes;
+
+    use crate::config::Config;
+    use crate::test_support::{app_state, new_image};
+    use crate::AppState;
+
+    async fn upload(state: &AppState, with_original: bool) -> i64 {
+        let image = new_image("image/jpeg");
+        let id = state.insert(&image).await.unwrap();
+        if with_original {
+            let data = Bytes::from_static(b"jpeg");
+            state
+                .blob_store()
+                .put(&original_key(id), data)
+                .await
+                .unwrap();
+        }
+        id
+    }
+
+    #[tokio::test]
+    async fn reconciles_rows_and_blobs_both_ways() {
+        let state = app_state(Config::default()).await;
+        let complete = upload(&state, true).await;
+        let without_original = upload(&state, false).await;
+        let store = state.blob_store();
+        store.put("99.jpg", Bytes::new()).await.unwrap();
+        store.put("99_files/9/0_0.jpg", Bytes::new()).await.unwrap();
+
+        // Everything is brand new, so only the thumbnails are queued.
+        let report = reconcile(&*state, RECONCILE_GRACE, true).await.unwrap();
+        assert_eq!(report.queued, vec![complete]);
+        assert!(report.missing_originals.is_empty());
+        assert!(report.orphaned_blobs.is_empty());
+
+        let dry_run = reconcile(&*state, Duration::ZERO, true).await.unwrap();
+        assert_eq!(dry_run.missing_originals, vec![without_original]);
+        assert_eq!(dry_run.orphaned_blobs, ["99.jpg", "99_files/9/0_0.jpg"]);
+        assert!(state.images_with_pending_jobs().await.unwrap().is_empty());
+        assert!(store.exists("99.jpg").await.unwrap());
+
+        let applied = reconcile(&*state, Duration::ZERO, false).await.unwrap();
+        assert_eq!(applied, dry_run);
+        assert_eq!(
+            state.images_with_pending_jobs().await.unwrap(),
+            vec![complete]
+        );
+        assert_eq!(store.list("").await.unwrap(), vec![original_key(complete)]);
+
+        // The queued job is pending, so nothing is left to do.
+        let again = reconcile(&*state, Duration::ZERO, false).await.unwrap();
+        assert!(again.is_empty());
+    }
+}
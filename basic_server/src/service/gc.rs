@@ -0,0 +1,245 @@
+// Garbage collection of the blob store: blobs of images that have no row
+// (deleted images, failed uploads) and cached variants that the current
+// configuration would never serve again.
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::Serialize;
+use thumbnail::{Fit, ThumbnailFormat, ThumbnailOptions};
+
+use crate::config::{Config, ConfigProvider};
+use crate::repository::image_repository::{ImageFilter, ImageRepository, ImageResult};
+use crate::service::image_service::variant_file_name;
+use crate::storage::{BlobStore, BlobStoreProvider};
+
+/// Why a blob is garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Garbage {
+    /// It belongs to an image without a row.
+    Orphaned,
+    /// A cached variant no preset or allowed size maps to, e.g. one rendered
+    /// with a preset's previous definition.
+    StaleVariant,
+}
+
+/// Blobs a collection found, and deleted unless it was a dry run.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub orphaned: Vec<String>,
+    pub stale_variants: Vec<String>,
+}
+
+impl GcReport {
+    /// Human readable summary, one blob per line.
+    pub fn lines(&self) -> Vec<String> {
+        let verb = if self.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        let mut lines = vec![format!(
+            "{verb} {} orphaned blobs and {} stale variants",
+            self.orphaned.len(),
+            self.stale_variants.len()
+        )];
+        lines.extend(self.orphaned.iter().map(|key| format!("  orphaned {key}")));
+        lines.extend(
+            self.stale_variants
+                .iter()
+                .map(|key| format!("  stale    {key}")),
+        );
+        lines
+    }
+}
+
+/// Deletes orphaned blobs and stale variants last modified more than `grace`
+/// ago; with `dry_run` only reports them.
+pub async fn collect_garbage<T>(repo: &T, grace: Duration, dry_run: bool) -> Result<GcReport>
+where
+    T: ImageRepository + ConfigProvider + BlobStoreProvider + ?Sized,
+{
+    let image_filter = ImageFilter {
+        id: None,
+        tags: None,
+        thumbnail_status: None,
+        sha256: None,
+    };
+    let images: HashSet<i64> = match repo.filter(image_filter).await? {
+        ImageResult::Multiple(images) => images.iter().map(|image| image.id).collect(),
+        ImageResult::Single(image) => HashSet::from([image.id]),
+    };
+
+    let store = repo.blob_store();
+    let modified_before = SystemTime::now() - grace;
+    let mut report = GcReport {
+        dry_run,
+        ..GcReport::default()
+    };
+    for key in store.list("").await? {
+        let Some(garbage) = classify(&key, &images, repo.config()) else {
+            continue;
+        };
+        if !settled(store, &key, modified_before).await? {
+            continue;
+        }
+        match garbage {
+            Garbage::Orphaned => report.orphaned.push(key),
+            Garbage::StaleVariant => report.stale_variants.push(key),
+        }
+    }
+
+    if !dry_run {
+        for key in report.orphaned.iter().chain(&report.stale_variants) {
+            store.delete(key).await?;
+        }
+    }
+    Ok(report)
+}
+
+/// Whether `key` is garbage given the images that have a row. Keys that do
+/// not belong to an image are never garbage.
+pub(crate) fn classify(key: &str, images: &HashSet<i64>, config: &Config) -> Option<Garbage> {
+    let id = image_id_of(key)?;
+    if !images.contains(&id) {
+        return Some(Garbage::Orphaned);
+    }
+    let derived = key.strip_prefix(&format!("{id}_"))?;
+    // Tiles are cut from the original and stay valid.
+    if derived.starts_with("files/") || serves_variant(config, id, key) {
+        return None;
+    }
+    Some(Garbage::StaleVariant)
+}
+
+/// Whether the blob was last modified before `modified_before`; younger blobs
+/// may belong to an upload or render in progress.
+pub(crate) async fn settled(
+    store: &dyn BlobStore,
+    key: &str,
+    modified_before: SystemTime,
+) -> Result<bool> {
+    Ok(store
+        .metadata(key)
+        .await?
+        .is_some_and(|metadata| metadata.last_modified <= modified_before))
+}
+
+// Image a blob belongs to: `7.jpg`, `7_thumbnail.jpg` and `7_files/..` all
+// belong to image 7.
+fn image_id_of(key: &str) -> Option<i64> {
+    let end = key.find(['.', '_'])?;
+    key[..end].parse().ok()
+}
+
+// Whether a request could still produce this variant key: it is a preset's,
+// or it has an allowed size and normalized parameters.
+fn serves_variant(config: &Config, id: i64, key: &str) -> bool {
+    if config
+        .presets
+        .iter()
+        .any(|preset| variant_file_name(id, &preset.options) == key)
+    {
+        return true;
+    }
+    let parse = || {
+        let (name, extension) = key.strip_prefix(&format!("{id}_"))?.rsplit_once('.')?;
+        let mut parts = name.split('_');
+        let (width, height) = parts.next()?.split_once('x')?;
+        let fit = Fit::from_name(parts.next()?)?;
+        let quality = parts.next()?.strip_prefix('q')?.parse().ok()?;
+        let format = ThumbnailFormat::from_extension(extension)?;
+        let options = ThumbnailOptions {
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+            fit,
+            format,
+            quality: format.normalize_quality(Some(quality)),
+        };
+        // Anything but the normalized spelling was never served.
+        Some(
+            variant_file_name(id, &options) == key
+                && config.is_allowed_size(options.width, options.height),
+        )
+    };
+    parse().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Bytes;
+
+    use crate::storage::original_key;
+    use crate::test_support::{app_state, new_image};
+
+    #[test]
+    fn blobs_are_attributed_to_their_image() {
+        assert_eq!(image_id_of("7.jpg"), Some(7));
+        assert_eq!(image_id_of("7_thumbnail_100x100.webp"), Some(7));
+        assert_eq!(image_id_of("12_files/9/0_0.jpg"), Some(12));
+        assert_eq!(image_id_of("ab/cd"), None);
+        assert_eq!(image_id_of("notes.txt"), None);
+    }
+
+    #[test]
+    fn classifies_blobs() {
+        let config = Config::default();
+        let images = HashSet::from([7]);
+        let classify = |key| classify(key, &images, &config);
+
+        assert_eq!(classify("7.jpg"), None);
+        assert_eq!(classify("7_files/9/0_0.jpg"), None);
+        // The `thumbnail` preset, and a query variant of an allowed size.
+        assert_eq!(classify("7_100x100_contain_q75.jpg"), None);
+        assert_eq!(classify("7_640x480_cover_q100.webp"), None);
+        assert_eq!(classify("notes.txt"), None);
+
+        assert_eq!(classify("8.jpg"), Some(Garbage::Orphaned));
+        assert_eq!(classify("8_files/9/0_0.jpg"), Some(Garbage::Orphaned));
+        // Cached before presets and allowed sizes changed, or by older versions.
+        assert_eq!(classify("7_thumbnail.jpg"), Some(Garbage::StaleVariant));
+        assert_eq!(
+            classify("7_50x50_contain_q75.jpg"),
+            Some(Garbage::StaleVariant)
+        );
+        assert_eq!(
+            classify("7_640x480_cover_q83.jpg"),
+            Some(Garbage::StaleVariant)
+        );
+    }
+
+    #[tokio::test]
+    async fn collects_only_settled_garbage() {
+        let state = app_state(Config::default()).await;
+        let image = new_image("image/jpeg");
+        let id = state.insert(&image).await.unwrap();
+        let store = state.blob_store();
+        for key in [
+            original_key(id),
+            format!("{id}_thumbnail.jpg"),
+            "99.jpg".to_string(),
+        ] {
+            store.put(&key, Bytes::new()).await.unwrap();
+        }
+
+        let young = collect_garbage(&*state, Duration::from_secs(3600), false)
+            .await
+            .unwrap();
+        assert!(young.orphaned.is_empty() && young.stale_variants.is_empty());
+
+        let dry_run = collect_garbage(&*state, Duration::ZERO, true)
+            .await
+            .unwrap();
+        assert_eq!(dry_run.orphaned, ["99.jpg"]);
+        assert_eq!(dry_run.stale_variants, [format!("{id}_thumbnail.jpg")]);
+        assert_eq!(store.list("").await.unwrap().len(), 3);
+
+        collect_garbage(&*state, Duration::ZERO, false)
+            .await
+            .unwrap();
+        assert_eq!(store.list("").await.unwrap(), [original_key(id)]);
+    }
+}
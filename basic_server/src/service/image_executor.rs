@@ -0,0 +1,234 @@
+// Runs CPU-heavy image work (decoding, resizing, encoding) on the blocking
+// pool, but never more than a configured number of jobs at a time, and lets
+// interactive requests overtake queued background work.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+
+/// Who is waiting for a piece of image work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Backfill and maintenance, e.g. regenerating presets for all images.
+    Background,
+    /// A client is waiting: on-the-fly variants, tiles, fresh uploads.
+    Interactive,
+}
+
+impl Priority {
+    /// Stored form; higher ranks are served first.
+    pub fn rank(&self) -> i64 {
+        match self {
+            Priority::Background => 0,
+            Priority::Interactive => 1,
+        }
+    }
+
+    pub fn from_rank(rank: i64) -> Self {
+        if rank > 0 {
+            Priority::Interactive
+        } else {
+            Priority::Background
+        }
+    }
+}
+
+/// A bounded executor for image processing with two priority levels.
+#[derive(Debug, Clone)]
+pub struct ImageExecutor {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    idle: usize,
+    interactive: VecDeque<oneshot::Sender<()>>,
+    background: VecDeque<oneshot::Sender<()>>,
+}
+
+impl State {
+    // Hands a freed slot to the next live waiter, interactive ones first.
+    fn release(&mut self) {
+        while let Some(waiter) = self
+            .interactive
+            .pop_front()
+            .or_else(|| self.background.pop_front())
+        {
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+        self.idle += 1;
+    }
+}
+
+impl ImageExecutor {
+    /// An executor running at most `threads` jobs at once.
+    pub fn new(threads: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                idle: threads.max(1),
+                interactive: VecDeque::new(),
+                background: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Runs `work` on the blocking pool once a slot is free. Waiting
+    /// interactive work always gets the next free slot before background work.
+    pub async fn run<R, F>(&self, priority: Priority, work: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let permit = self.acquire(priority).await;
+        // The permit moves into the task, so the slot stays taken until the
+        // work is done even if the caller stops waiting.
+        Ok(spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await?)
+    }
+
+    async fn acquire(&self, priority: Priority) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.idle > 0 {
+                state.idle -= 1;
+                return Permit {
+                    state: self.state.clone(),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            match priority {
+                Priority::Interactive => state.interactive.push_back(sender),
+                Priority::Background => state.background.push_back(sender),
+            }
+            receiver
+        };
+        let mut waiting = Waiting {
+            state: self.state.clone(),
+            receiver: Some(receiver),
+        };
+        // Senders only go away by being sent on, so this cannot fail.
+        let _ = waiting.receiver.as_mut().unwrap().await;
+        waiting.receiver = None;
+        Permit {
+            state: self.state.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    fn waiting(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.interactive.len() + state.background.len()
+    }
+}
+
+/// A taken slot; dropping it passes the slot on to the next waiter.
+#[derive(Debug)]
+struct Permit {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release();
+    }
+}
+
+// Returns a slot that was handed to a waiter which stopped waiting before it
+// could take it.
+struct Waiting {
+    state: Arc<Mutex<State>>,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if receiver.try_recv().is_ok() {
+                self.state.lock().unwrap().release();
+            }
+        }
+    }
+}
+
+/// Gives handlers and workers access to the shared image executor.
+pub trait ImageExecutorProvider: Send + Sync + 'static {
+    fn image_executor(&self) -> &ImageExecutor;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait_for_waiters(executor: &ImageExecutor, count: usize) {
+        while executor.waiting() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn interactive_work_overtakes_background_work() {
+        let executor = ImageExecutor::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let busy = tokio::spawn({
+            let executor = executor.clone();
+            async move {
+                executor
+                    .run(Priority::Background, move || released.recv().unwrap())
+                    .await
+            }
+        });
+        while executor.state.lock().unwrap().idle > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let mut queued = Vec::new();
+        for (name, priority) in [
+            ("backfill", Priority::Background),
+            ("thumbnail", Priority::Interactive),
+        ] {
+            let (runner, order) = (executor.clone(), order.clone());
+            queued.push(tokio::spawn(async move {
+                runner
+                    .run(priority, move || order.lock().unwrap().push(name))
+                    .await
+            }));
+            wait_for_waiters(&executor, queued.len()).await;
+        }
+
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap();
+        for task in queued {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["thumbnail", "backfill"]);
+        assert_eq!(executor.state.lock().unwrap().idle, 1);
+    }
+
+    #[tokio::test]
+    async fn abandoned_waiters_do_not_leak_slots() {
+        let executor = ImageExecutor::new(1);
+        let permit = executor.acquire(Priority::Interactive).await;
+
+        let waiter = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.acquire(Priority::Background).await }
+        });
+        wait_for_waiters(&executor, 1).await;
+        waiter.abort();
+        let _ = waiter.await;
+
+        drop(permit);
+        assert_eq!(executor.run(Priority::Background, || 42).await.unwrap(), 42);
+        assert_eq!(executor.state.lock().unwrap().idle, 1);
+    }
+}
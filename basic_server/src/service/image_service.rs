@@ -0,0 +1,243 @@
+use serde::Deserialize;
+use thumbnail::{Fit, ThumbnailFormat, ThumbnailOptions};
+
+use crate::config::Config;
+
+/// Query string of `/thumbnails/:id`, e.g. `?w=320&h=240&fit=cover&fmt=webp&q=80`.
+#[derive(Debug, Default, Deserialize)]
+pub struct VariantQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<String>,
+    pub fmt: Option<String>,
+    pub q: Option<u8>,
+}
+
+impl VariantQuery {
+    pub fn is_empty(&self) -> bool {
+        self.w.is_none()
+            && self.h.is_none()
+            && self.fit.is_none()
+            && self.fmt.is_none()
+            && self.q.is_none()
+    }
+}
+
+/// A validated and normalized variant request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariantRequest {
+    pub options: ThumbnailOptions,
+    /// The format came from the `Accept` header rather than `fmt`.
+    pub negotiated: bool,
+}
+
+/// Validates a variant query against the configured size allow-list and fills
+/// in defaults. The error is a message suitable for a `400 Bad Request`.
+pub fn resolve_variant(
+    query: &VariantQuery,
+    accept: Option<&str>,
+    config: &Config,
+) -> Result<VariantRequest, String> {
+    let (Some(width), Some(height)) = (query.w, query.h) else {
+        return Err("both `w` and `h` are required".to_string());
+    };
+    if !config.is_allowed_size(width, height) {
+        return Err(format!("size {width}x{height} is not allowed"));
+    }
+
+    let fit = match query.fit.as_deref() {
+        Some(name) => Fit::from_name(name).ok_or_else(|| format!("unknown fit `{name}`"))?,
+        None => Fit::default(),
+    };
+    let (format, negotiated) = match query.fmt.as_deref() {
+        Some(extension) => (
+            ThumbnailFormat::from_extension(extension)
+                .ok_or_else(|| format!("unsupported format `{extension}`"))?,
+            false,
+        ),
+        None => (negotiate_thumbnail_format(accept), true),
+    };
+
+    Ok(VariantRequest {
+        options: ThumbnailOptions {
+            width,
+            height,
+            fit,
+            format,
+            quality: format.normalize_quality(query.q),
+        },
+        negotiated,
+    })
+}
+
+/// Cache file name of a variant; every normalized parameter is part of it.
+pub fn variant_file_name(id: i64, options: &ThumbnailOptions) -> String {
+    format!(
+        "{id}_{}x{}_{}_q{}.{}",
+        options.width,
+        options.height,
+        options.fit.as_str(),
+        options.quality,
+        options.format.extension()
+    )
+}
+
+/// Picks the thumbnail encoding for a request based on its `Accept` header.
+///
+/// Media ranges are weighted by their `q` parameter; among equally weighted
+/// candidates the order of `ThumbnailFormat::supported()` wins. Without a
+/// usable header, or when nothing we can encode is acceptable, JPEG is used.
+pub fn negotiate_thumbnail_format(accept: Option<&str>) -> ThumbnailFormat {
+    let Some(accept) = accept else {
+        return ThumbnailFormat::Jpeg;
+    };
+
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(parse_media_range).collect();
+
+    let mut best: Option<(ThumbnailFormat, f32)> = None;
+    for format in ThumbnailFormat::supported() {
+        // The most specific matching range decides, so `image/avif;q=0` beats `image/*`.
+        let quality = ranges
+            .iter()
+            .filter_map(|(range, q)| {
+                media_range_specificity(range, format.mime_type()).map(|s| (s, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+
+        if quality > best.map_or(0.0, |(_, best_q)| best_q) {
+            best = Some((*format, quality));
+        }
+    }
+
+    best.map(|(format, _)| format)
+        .unwrap_or(ThumbnailFormat::Jpeg)
+}
+
+fn parse_media_range(entry: &str) -> Option<(&str, f32)> {
+    let mut parts = entry.split(';');
+    let range = parts.next()?.trim();
+    if range.is_empty() {
+        return None;
+    }
+
+    let quality = parts
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+    Some((range, quality))
+}
+
+// Returns how specifically `range` matches `mime`: 2 for an exact type,
+// 1 for `type/*`, 0 for `*/*`, and None when it does not match at all.
+fn media_range_specificity(range: &str, mime: &str) -> Option<u8> {
+    if range == "*/*" {
+        return Some(0);
+    }
+    match range.strip_suffix("/*") {
+        Some(kind) => mime
+            .split('/')
+            .next()
+            .filter(|mime_kind| mime_kind.eq_ignore_ascii_case(kind))
+            .map(|_| 1),
+        None => range.eq_ignore_ascii_case(mime).then_some(2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(w: u32, h: u32) -> VariantQuery {
+        VariantQuery {
+            w: Some(w),
+            h: Some(h),
+            ..VariantQuery::default()
+        }
+    }
+
+    #[test]
+    fn variant_defaults_and_normalization() {
+        let mut query = query(320, 240);
+        query.fmt = Some("jpeg".to_string());
+        query.q = Some(83);
+
+        let variant = resolve_variant(&query, None, &Config::default()).unwrap();
+
+        assert!(!variant.negotiated);
+        assert_eq!(variant.options.fit, Fit::Contain);
+        assert_eq!(variant.options.quality, 85);
+        assert_eq!(
+            variant_file_name(7, &variant.options),
+            "7_320x240_contain_q85.jpg"
+        );
+    }
+
+    #[test]
+    fn variant_sizes_must_be_allowed() {
+        let config = Config::default();
+
+        assert!(resolve_variant(&query(321, 240), None, &config).is_err());
+        assert!(resolve_variant(
+            &VariantQuery {
+                w: Some(320),
+                ..VariantQuery::default()
+            },
+            None,
+            &config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn lossless_variants_ignore_quality() {
+        let mut query = query(100, 100);
+        query.fmt = Some("webp".to_string());
+        query.q = Some(30);
+
+        let variant = resolve_variant(&query, None, &Config::default()).unwrap();
+
+        assert_eq!(variant.options.quality, 100);
+    }
+
+    #[test]
+    fn variant_format_is_negotiated_without_fmt() {
+        let variant =
+            resolve_variant(&query(100, 100), Some("image/webp"), &Config::default()).unwrap();
+
+        assert!(variant.negotiated);
+        assert_eq!(variant.options.format, ThumbnailFormat::WebP);
+    }
+
+    #[test]
+    fn missing_header_falls_back_to_jpeg() {
+        assert_eq!(negotiate_thumbnail_format(None), ThumbnailFormat::Jpeg);
+    }
+
+    #[test]
+    fn browser_accept_header_prefers_modern_formats() {
+        let accept = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+        let expected = ThumbnailFormat::supported()[0];
+        assert_eq!(negotiate_thumbnail_format(Some(accept)), expected);
+    }
+
+    #[test]
+    fn quality_values_are_respected() {
+        let accept = "image/webp;q=0.2, image/jpeg";
+        assert_eq!(
+            negotiate_thumbnail_format(Some(accept)),
+            ThumbnailFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn explicitly_refused_formats_are_skipped() {
+        let accept = "image/avif;q=0, image/webp;q=0, image/*";
+        assert_eq!(
+            negotiate_thumbnail_format(Some(accept)),
+            ThumbnailFormat::Jpeg
+        );
+    }
+}
@@ -0,0 +1,261 @@
+// Workers that drain the persistent job queue: a fixed number of tasks claim
+// jobs one at a time, so thumbnail generation never runs unbounded.
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use thumbnail::{ThumbnailError, GENERATOR_VERSION};
+use tokio::time::{sleep, timeout};
+
+use crate::config::ConfigProvider;
+use crate::repository::image_repository::ImageRepository;
+use crate::repository::job_repository::{unix_now, Job, JobRepository, WORKER_STOPPED};
+use crate::routes::image_routes::{delete_derived_blobs, find_image, generate_presets};
+use crate::service::image_executor::ImageExecutorProvider;
+use crate::storage::BlobStoreProvider;
+
+/// A single run is abandoned after this long and retried.
+const JOB_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// `running` jobs older than this belong to a worker that no longer exists.
+/// Must exceed `JOB_TIMEOUT`, or live jobs would be requeued.
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// How often idle workers look for retries that became due.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Starts `config.job_workers` workers and the stale job recovery.
+///
+/// The database belongs to this one server process, so before any worker
+/// starts, every `running` job was left behind by a previous run and is
+/// recovered right away rather than after `STALE_AFTER`.
+pub fn spawn_job_workers<T>(repo: Arc<T>)
+where
+    T: JobRepository + ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    tokio::spawn(async move {
+        recover_jobs_started_before(&*repo, unix_now() + 1).await;
+        for _ in 0..repo.config().job_workers {
+            tokio::spawn(work(repo.clone()));
+        }
+        recover_stale_jobs(repo).await
+    });
+}
+
+async fn work<T>(repo: Arc<T>)
+where
+    T: JobRepository + ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    loop {
+        match repo.claim_next().await {
+            Ok(Some(job)) => run(repo.clone(), job).await,
+            Ok(None) => {
+                tokio::select! {
+                    _ = repo.job_enqueued() => {}
+                    _ = sleep(POLL_INTERVAL) => {}
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to claim a job: {e}");
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn recover_stale_jobs<T: JobRepository + ImageRepository + ConfigProvider>(repo: Arc<T>) {
+    loop {
+        sleep(RECOVERY_INTERVAL).await;
+        recover_jobs_started_before(&*repo, unix_now() - STALE_AFTER.as_secs() as i64).await;
+    }
+}
+
+// Requeues the `running` jobs started before `started_before`, or fails them
+// along with their image's thumbnails once they are out of attempts.
+async fn recover_jobs_started_before<T: JobRepository + ImageRepository + ConfigProvider>(
+    repo: &T,
+    started_before: i64,
+) {
+    let max_attempts = repo.config().job_max_attempts;
+    let stale = match repo.requeue_stale(started_before, max_attempts).await {
+        Ok(stale) => stale,
+        Err(e) => {
+            eprintln!("Failed to requeue stale jobs: {e}");
+            return;
+        }
+    };
+    if stale.requeued > 0 {
+        println!("Requeued {} jobs of stopped workers", stale.requeued);
+    }
+    for job in stale.failed {
+        eprintln!(
+            "Job {} for image {} failed: {WORKER_STOPPED} on each of {} attempts",
+            job.id, job.image_id, job.attempts
+        );
+        let result = Err(anyhow!(WORKER_STOPPED));
+        if let Err(e) = update_thumbnail_status(repo, &job, &result, false).await {
+            eprintln!(
+                "Failed to update thumbnail status of image {}: {e}",
+                job.image_id
+            );
+        }
+    }
+}
+
+// Runs a claimed job and records the outcome.
+async fn run<T>(repo: Arc<T>, job: Job)
+where
+    T: JobRepository + ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    let result = timeout(JOB_TIMEOUT, generate(repo.clone(), &job))
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", JOB_TIMEOUT.as_secs())));
+
+    let missing_original = result.as_ref().is_err_and(|e| {
+        matches!(
+            e.downcast_ref::<ThumbnailError>(),
+            Some(ThumbnailError::NotFound(_))
+        )
+    });
+    let retrying =
+        result.is_err() && !missing_original && job.attempts < repo.config().job_max_attempts;
+    let recorded = match &result {
+        Ok(()) => {
+            println!("Thumbnails created for image {}", job.image_id);
+            repo.complete(job.id).await
+        }
+        Err(e) => {
+            let retry_at =
+                retrying.then(|| unix_now() + retry_delay(job.attempts).as_secs() as i64);
+            match retry_at {
+                Some(_) => eprintln!(
+                    "Job {} for image {} failed (attempt {}), will retry: {e}",
+                    job.id, job.image_id, job.attempts
+                ),
+                None => eprintln!("Job {} for image {} failed: {e}", job.id, job.image_id),
+            }
+            repo.fail(job.id, &e.to_string(), retry_at).await
+        }
+    };
+    if let Err(e) = recorded {
+        eprintln!("Failed to record the outcome of job {}: {e}", job.id);
+    }
+
+    // The original is gone, so the record can never get thumbnails.
+    if missing_original {
+        remove_image_without_original(&*repo, job.image_id).await;
+    } else if let Err(e) = update_thumbnail_status(&*repo, &job, &result, retrying).await {
+        eprintln!(
+            "Failed to update thumbnail status of image {}: {e}",
+            job.image_id
+        );
+    }
+}
+
+async fn generate<T>(repo: Arc<T>, job: &Job) -> Result<()>
+where
+    T: ImageRepository + ConfigProvider + BlobStoreProvider + ImageExecutorProvider,
+{
+    let presets = repo
+        .config()
+        .presets
+        .iter()
+        .filter(|preset| match &job.presets {
+            Some(names) => names.contains(&preset.name),
+            None => true,
+        })
+        .cloned()
+        .collect();
+    generate_presets(repo, job.image_id, presets, job.priority).await
+}
+
+// Reflects the outcome of a run in the image's thumbnail status. While a job
+// is retried the image stays pending, with the error of the last attempt.
+async fn update_thumbnail_status<T: ImageRepository + ?Sized>(
+    repo: &T,
+    job: &Job,
+    result: &Result<()>,
+    retrying: bool,
+) -> Result<()> {
+    let Some(mut image) = find_image(repo, job.image_id).await? else {
+        return Ok(());
+    };
+    match result {
+        // Regenerating single presets does not make a pending image ready.
+        Ok(()) if job.presets.is_some() => return Ok(()),
+        Ok(()) => image.thumbnails_ready(GENERATOR_VERSION),
+        Err(e) if retrying => image.thumbnail_error = Some(e.to_string()),
+        Err(e) => image.thumbnails_failed(e.to_string()),
+    }
+    repo.update(image).await
+}
+
+async fn remove_image_without_original<T: ImageRepository + BlobStoreProvider>(repo: &T, id: i64) {
+    println!("File not found, deleting from DB...");
+    match repo.delete(id).await {
+        Ok(_) => {
+            println!("Image {id} deleted successfully.");
+            if let Err(e) = delete_derived_blobs(repo.blob_store(), id).await {
+                eprintln!("Failed to delete variants of image {id}: {e}");
+            }
+        }
+        Err(e) => eprintln!("Failed to delete image {id}: {e}"),
+    }
+}
+
+/// Delay before retrying after the `attempts`-th failed run: doubling from
+/// `FIRST_RETRY_DELAY` up to `MAX_RETRY_DELAY`.
+fn retry_delay(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    FIRST_RETRY_DELAY
+        .saturating_mul(factor)
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::repository::image_repository::ThumbnailStatus;
+    use crate::repository::job_repository::{JobState, NewJob};
+    use crate::service::image_executor::Priority;
+    use crate::test_support::{app_state, new_image};
+
+    #[tokio::test]
+    async fn orphaned_jobs_out_of_attempts_fail_their_image() {
+        let config = Config {
+            job_max_attempts: 1,
+            ..Config::default()
+        };
+        let state = app_state(config).await;
+        let new_image = new_image("image/png");
+        let image_id = state.insert(&new_image).await.unwrap();
+        let job = NewJob {
+            image_id,
+            presets: None,
+            priority: Priority::Interactive,
+        };
+        let job_id = state.enqueue(&job).await.unwrap();
+        // Claimed by a worker of a process that then died.
+        state.claim_next().await.unwrap();
+
+        recover_jobs_started_before(&*state, unix_now() + 1).await;
+
+        let job = state.job(job_id).await.unwrap().unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        let image = find_image(&*state, image_id).await.unwrap().unwrap();
+        assert_eq!(image.thumbnail_status, ThumbnailStatus::Failed);
+        assert_eq!(image.thumbnail_error.as_deref(), Some(WORKER_STOPPED));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_cap() {
+        assert_eq!(retry_delay(1), Duration::from_secs(5));
+        assert_eq!(retry_delay(2), Duration::from_secs(10));
+        assert_eq!(retry_delay(4), Duration::from_secs(40));
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+    }
+}
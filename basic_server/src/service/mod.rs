@@ -1 +1,7 @@
-pub mod image_service;
\ No newline at end of file
+pub mod backfill;
+pub mod gc;
+pub mod image_executor;
+pub mod image_service;
+pub mod job_queue;
+pub mod staged_upload;
+pub mod url_signer;
@@ -0,0 +1,105 @@
+// Streams uploads to a temporary file, hashing and counting the bytes on the
+// way, so an original is never held in memory as a whole.
+use std::path::Path;
+
+use axum::body::Bytes;
+use futures::{Stream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+
+use crate::hex;
+
+/// Bytes kept from the start of an upload, enough to sniff any image format.
+const HEAD_LEN: usize = 512;
+
+/// An upload written to a temporary file. The file is deleted when this is
+/// dropped, unless it was handed to `BlobStore::put_file`.
+#[derive(Debug)]
+pub struct StagedUpload {
+    pub file: TempPath,
+    pub size: u64,
+    /// SHA-256 of the content as lowercase hex.
+    pub sha256: String,
+    /// The first bytes of the content.
+    pub head: Vec<u8>,
+}
+
+/// Why an upload could not be staged.
+#[derive(Debug)]
+pub enum StageError<E> {
+    /// The upload stream failed, e.g. the client went away or sent too much.
+    Read(E),
+    /// The temporary file could not be written.
+    Write(std::io::Error),
+}
+
+/// Writes `chunks` to a new temporary file in `dir`.
+pub async fn stage_upload<S, E>(mut chunks: S, dir: &Path) -> Result<StagedUpload, StageError<E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let (file, path) = tempfile::NamedTempFile::new_in(dir)
+        .map_err(StageError::Write)?
+        .into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut head = Vec::new();
+
+    while let Some(chunk) = chunks.try_next().await.map_err(StageError::Read)? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        let wanted = HEAD_LEN.saturating_sub(head.len()).min(chunk.len());
+        head.extend_from_slice(&chunk[..wanted]);
+        file.write_all(&chunk).await.map_err(StageError::Write)?;
+    }
+    file.flush().await.map_err(StageError::Write)?;
+
+    Ok(StagedUpload {
+        file: path,
+        size,
+        sha256: hex::encode(&hasher.finalize()),
+        head,
+    })
+}
+
+/// SHA-256 of `data` as lowercase hex, as recorded for staged uploads.
+#[cfg(test)]
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stages_chunks_with_their_size_and_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks = [Bytes::from(vec![7; 500]), Bytes::from(vec![8; 100])];
+        let stream = futures::stream::iter(chunks.map(Ok::<_, std::io::Error>));
+
+        let staged = stage_upload(stream, dir.path()).await.unwrap();
+        assert_eq!(staged.size, 600);
+        assert_eq!(staged.head.len(), HEAD_LEN);
+        assert_eq!(&staged.head[498..502], [7, 7, 8, 8]);
+        let content = std::fs::read(&staged.file).unwrap();
+        assert_eq!(content.len(), 600);
+        assert_eq!(staged.sha256, sha256_hex(&content));
+
+        let path = staged.file.to_path_buf();
+        drop(staged);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_leave_no_file_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks = [Ok(Bytes::from_static(b"partial")), Err("connection reset")];
+
+        let result = stage_upload(futures::stream::iter(chunks), dir.path()).await;
+        assert!(matches!(result, Err(StageError::Read("connection reset"))));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt::{Debug, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Query parameter carrying the signature.
+pub const SIGNATURE_PARAM: &str = "sig";
+/// Optional query parameter with a unix timestamp after which the URL is void.
+pub const EXPIRES_PARAM: &str = "exp";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Invalid,
+    Expired,
+}
+
+/// Signs and verifies URLs with HMAC-SHA256 over the path and query string.
+///
+/// The signature covers everything except the `sig` parameter itself, in the
+/// order the parameters appear, so any change to size, format or expiry
+/// invalidates it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+// Keeps the secret out of logs that print the configuration.
+impl Debug for UrlSigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UrlSigner { key: <redacted> }")
+    }
+}
+
+impl UrlSigner {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Returns `path_and_query` with `exp` (if given) and `sig` appended.
+    pub fn sign(&self, path_and_query: &str, expires_at: Option<u64>) -> String {
+        let mut url = path_and_query.to_string();
+        if let Some(expires_at) = expires_at {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(&format!("{EXPIRES_PARAM}={expires_at}"));
+        }
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&url).finalize().into_bytes());
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(&format!("{SIGNATURE_PARAM}={signature}"));
+        url
+    }
+
+    /// Like `sign`, with an expiry `ttl_secs` from now.
+    pub fn sign_for(&self, path_and_query: &str, ttl_secs: u64) -> String {
+        self.sign(path_and_query, Some(unix_now().saturating_add(ttl_secs)))
+    }
+
+    /// Checks the `sig` (and `exp`, if present) of a request's path and raw query.
+    pub fn verify(&self, path: &str, query: Option<&str>) -> Result<(), SignatureError> {
+        self.verify_at(path, query, unix_now())
+    }
+
+    fn verify_at(&self, path: &str, query: Option<&str>, now: u64) -> Result<(), SignatureError> {
+        let query = query.unwrap_or_default();
+        let mut signature = None;
+        let mut expires_at = None;
+        let mut signed_params = Vec::new();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some((SIGNATURE_PARAM, value)) => signature = Some(value),
+                Some((EXPIRES_PARAM, value)) => {
+                    expires_at = Some(value.parse::<u64>().map_err(|_| SignatureError::Invalid)?);
+                    signed_params.push(param);
+                }
+                _ => signed_params.push(param),
+            }
+        }
+
+        let signature = signature.ok_or(SignatureError::Missing)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| SignatureError::Invalid)?;
+
+        let mut url = path.to_string();
+        if !signed_params.is_empty() {
+            url.push('?');
+            url.push_str(&signed_params.join("&"));
+        }
+        self.mac(&url)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Invalid)?;
+
+        match expires_at {
+            Some(expires_at) if now > expires_at => Err(SignatureError::Expired),
+            _ => Ok(()),
+        }
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(url: &str) -> (&str, Option<&str>) {
+        match url.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (url, None),
+        }
+    }
+
+    #[test]
+    fn signed_urls_verify() {
+        let signer = UrlSigner::new("secret");
+        let url = signer.sign("/thumbnails/7?w=320&h=240", None);
+        let (path, query) = split(&url);
+
+        assert!(url.starts_with("/thumbnails/7?w=320&h=240&sig="));
+        assert_eq!(signer.verify(path, query), Ok(()));
+    }
+
+    #[test]
+    fn tampered_urls_are_rejected() {
+        let signer = UrlSigner::new("secret");
+        let url = signer.sign("/thumbnails/7?w=320&h=240", None);
+        let tampered = url.replace("w=320", "w=640");
+        let (path, query) = split(&tampered);
+
+        assert_eq!(signer.verify(path, query), Err(SignatureError::Invalid));
+        assert_eq!(
+            UrlSigner::new("other").verify(split(&url).0, split(&url).1),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            signer.verify("/images/7", None),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn expiry_is_signed_and_enforced() {
+        let signer = UrlSigner::new("secret");
+        let url = signer.sign("/images/7", Some(1_000));
+        let (path, query) = split(&url);
+
+        assert_eq!(signer.verify_at(path, query, 999), Ok(()));
+        assert_eq!(
+            signer.verify_at(path, query, 1_001),
+            Err(SignatureError::Expired)
+        );
+
+        let extended = url.replace("exp=1000", "exp=5000");
+        let (path, query) = split(&extended);
+        assert_eq!(
+            signer.verify_at(path, query, 1_001),
+            Err(SignatureError::Invalid)
+        );
+    }
+}
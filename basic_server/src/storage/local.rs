@@ -0,0 +1,527 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::task::spawn_blocking;
+use tokio_util::io::ReaderStream;
+
+use crate::storage::{validate_key, BlobMetadata, BlobStore, BlobStream};
+
+/// Name of the setting that records which layout the store writes.
+pub const LAYOUT_SETTING: &str = "storage_layout";
+
+/// How keys map to files below the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `root/<key>`; one directory ends up holding every original.
+    Flat,
+    /// `root/ab/cd/<key>`, where `ab/cd` is taken from a hash of the image
+    /// part of the key, so all blobs of an image share a shard directory.
+    Sharded,
+}
+
+impl Layout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Layout::Flat => "flat",
+            Layout::Sharded => "sharded",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flat" => Some(Layout::Flat),
+            "sharded" => Some(Layout::Sharded),
+            _ => None,
+        }
+    }
+}
+
+/// Stores blobs as files below a root directory, one file per key.
+///
+/// Blobs are written in the configured layout but found in either, so a
+/// server keeps working while `migrate-storage` moves files underneath it.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+    layout: Layout,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_layout(root, Layout::Flat)
+    }
+
+    pub fn with_layout(root: impl Into<PathBuf>, layout: Layout) -> Self {
+        Self {
+            root: root.into(),
+            layout,
+        }
+    }
+
+    // Where `key` is written, followed by where an older layout may have left it.
+    fn paths(&self, key: &str) -> Result<[PathBuf; 2]> {
+        validate_key(key)?;
+        let flat = self.root.join(key);
+        let sharded = self.root.join(shard(key)).join(key);
+        Ok(match self.layout {
+            Layout::Flat => [flat, sharded],
+            Layout::Sharded => [sharded, flat],
+        })
+    }
+
+    async fn open(&self, key: &str) -> Result<Option<File>> {
+        for path in self.paths(key)? {
+            match File::open(path).await {
+                Ok(file) => return Ok(Some(file)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("failed to open blob `{key}`")),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        let [path, stale] = self.paths(key)?;
+        spawn_blocking(move || {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            write_atomically(&path, &data)?;
+            // Drop any copy in the other layout; a migration would otherwise
+            // keep whichever one sits in the shard.
+            remove_if_exists(&stale)
+        })
+        .await?
+    }
+
+    async fn put_file(&self, key: &str, file: TempPath) -> Result<()> {
+        let [path, stale] = self.paths(key)?;
+        spawn_blocking(move || {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            std::fs::create_dir_all(dir)?;
+            // A rename is atomic and copies nothing, but only works within a
+            // file system; otherwise copy next to the target first.
+            if let Err(e) = file.persist(&path) {
+                let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+                std::io::copy(&mut std::fs::File::open(&e.path)?, &mut temp_file)?;
+                temp_file.persist(&path)?;
+            }
+            remove_if_exists(&stale)
+        })
+        .await?
+        .with_context(|| format!("failed to store blob `{key}`"))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<BlobStream>> {
+        let file = self.open(key).await?;
+        Ok(file.map(|file| ReaderStream::new(file).boxed()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let paths = self.paths(key)?;
+        spawn_blocking(move || paths.iter().try_for_each(|path| remove_if_exists(path)))
+            .await?
+            .with_context(|| format!("failed to delete blob `{key}`"))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.metadata(key).await?.is_some())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        spawn_blocking(move || {
+            let mut keys = Vec::new();
+            collect_flat_keys(&root, &mut keys)?;
+            // A prefix naming a whole image lives in a single shard.
+            let shards = match image_part(&prefix) {
+                Some(image) => vec![root.join(shard(image))],
+                None => shard_dirs(&root)?,
+            };
+            for dir in shards {
+                collect_keys(&dir, "", &mut keys)?;
+            }
+            keys.retain(|key| key.starts_with(&prefix));
+            keys.sort();
+            keys.dedup();
+            Ok(keys)
+        })
+        .await?
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<BlobMetadata>> {
+        for path in self.paths(key)? {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) => {
+                    return Ok(Some(BlobMetadata {
+                        len: metadata.len(),
+                        last_modified: metadata.modified()?,
+                    }))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("failed to stat blob `{key}`")),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Option<BlobStream>> {
+        let Some(mut file) = self.open(key).await? else {
+            return Ok(None);
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let reader = file.take(range.end.saturating_sub(range.start));
+        Ok(Some(ReaderStream::new(reader).boxed()))
+    }
+}
+
+/// Outcome of [`migrate_to_sharded`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Files moved into their shard (or that would be, in a dry run).
+    pub moved: usize,
+    /// Flat files dropped because the shard already held a newer copy.
+    pub superseded: usize,
+}
+
+/// Moves every blob still in the flat layout below `root` into its shard.
+///
+/// Each file is moved with a single rename, so the store stays readable
+/// throughout and an interrupted run can simply be started again.
+pub fn migrate_to_sharded(root: &Path, dry_run: bool) -> Result<MigrationReport> {
+    let mut keys = Vec::new();
+    collect_flat_keys(root, &mut keys)?;
+
+    let mut report = MigrationReport::default();
+    for key in keys {
+        let from = root.join(&key);
+        let to = root.join(shard(&key)).join(&key);
+        if to.exists() {
+            // Written by a sharded server after the flat copy; the flat one is stale.
+            report.superseded += 1;
+            if !dry_run {
+                remove_if_exists(&from)?;
+            }
+        } else {
+            report.moved += 1;
+            if !dry_run {
+                if let Some(dir) = to.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                std::fs::rename(&from, &to)
+                    .with_context(|| format!("failed to move {}", from.display()))?;
+            }
+        }
+        let done = report.moved + report.superseded;
+        if done % 10_000 == 0 {
+            println!("migrate-storage: {done} files processed");
+        }
+    }
+    if !dry_run {
+        for entry in read_dir_if_exists(root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir()
+                && !is_shard_dir_name(&name)
+                && remove_empty_dirs(&entry.path())?
+            {
+                std::fs::remove_dir(entry.path())?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+// Shard directory of a key: two levels named after the first bytes of a hash
+// of its image part, e.g. `7_files/9/0_0.jpg` -> `f6/e0`.
+fn shard(key: &str) -> PathBuf {
+    let image = image_part(key).unwrap_or(key);
+    let hash = Sha256::digest(image.as_bytes());
+    Path::new(&format!("{:02x}", hash[0])).join(format!("{:02x}", hash[1]))
+}
+
+// The image a key belongs to: everything before the first `.`, `_` or `/`.
+// None if the key (or prefix) does not reach that far.
+fn image_part(key: &str) -> Option<&str> {
+    key.find(['.', '_', '/']).map(|end| &key[..end])
+}
+
+fn is_shard_dir_name(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+// All `ab/cd` shard directories below `root`.
+fn shard_dirs(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for outer in read_dir_if_exists(root)? {
+        let outer = outer?;
+        if !outer.file_type()?.is_dir() || !is_shard_dir_name(&outer.file_name().to_string_lossy())
+        {
+            continue;
+        }
+        for inner in std::fs::read_dir(outer.path())? {
+            let inner = inner?;
+            if inner.file_type()?.is_dir()
+                && is_shard_dir_name(&inner.file_name().to_string_lossy())
+            {
+                dirs.push(inner.path());
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+// Keys stored in the flat layout: everything below `root` except the shards.
+fn collect_flat_keys(root: &Path, keys: &mut Vec<String>) -> Result<()> {
+    for entry in read_dir_if_exists(root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(".tmp") {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            if !is_shard_dir_name(&name) {
+                collect_keys(&entry.path(), &format!("{name}/"), keys)?;
+            }
+        } else {
+            keys.push(name);
+        }
+    }
+    Ok(())
+}
+
+fn read_dir_if_exists(dir: &Path) -> Result<Vec<std::io::Result<std::fs::DirEntry>>> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Removes the directories below `dir` (such as `7_files/9`) that a migration
+// left empty. Returns whether `dir` itself is now empty.
+fn remove_empty_dirs(dir: &Path) -> Result<bool> {
+    let mut empty = true;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && remove_empty_dirs(&entry.path())? {
+            std::fs::remove_dir(entry.path())?;
+        } else {
+            empty = false;
+        }
+    }
+    Ok(empty)
+}
+
+// Writes under a temporary name and renames into place, so concurrent requests
+// never stream a half-written file.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut temp_file, data)?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
+// Walks `dir` recursively, skipping the temporary files of in-flight writes.
+fn collect_keys(dir: &Path, prefix: &str, keys: &mut Vec<String>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(".tmp") {
+            continue;
+        }
+        let key = format!("{prefix}{name}");
+        if entry.file_type()?.is_dir() {
+            collect_keys(&entry.path(), &format!("{key}/"), keys)?;
+        } else {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_nested_keys_below_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path());
+
+        store
+            .put("7_files/9/0_0.jpg", Bytes::from_static(b"tile"))
+            .await
+            .unwrap();
+        store
+            .put("7.jpg", Bytes::from_static(b"original"))
+            .await
+            .unwrap();
+
+        assert!(dir.path().join("7_files/9/0_0.jpg").is_file());
+        assert_eq!(
+            store.list("7").await.unwrap(),
+            vec!["7.jpg", "7_files/9/0_0.jpg"]
+        );
+        assert_eq!(
+            store.get_bytes("7.jpg").await.unwrap().unwrap(),
+            b"original"
+        );
+        assert!(store.put("../escape", Bytes::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn staged_files_are_moved_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path().join("store"));
+        let mut staged = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+        std::io::Write::write_all(&mut staged, b"original").unwrap();
+        let staged = staged.into_temp_path();
+        let staged_path = staged.to_path_buf();
+
+        store.put_file("7.jpg", staged).await.unwrap();
+
+        assert!(!staged_path.exists());
+        assert_eq!(
+            store.get_bytes("7.jpg").await.unwrap().unwrap(),
+            b"original"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path());
+        store
+            .put("a", Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+
+        let range: Vec<Bytes> =
+            futures::TryStreamExt::try_collect(store.get_range("a", 3..6).await.unwrap().unwrap())
+                .await
+                .unwrap();
+        assert_eq!(range.concat(), b"345");
+        assert_eq!(store.metadata("a").await.unwrap().unwrap().len, 10);
+
+        store.delete("a").await.unwrap();
+        store.delete("a").await.unwrap();
+        assert!(!store.exists("a").await.unwrap());
+        assert!(store.get("a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sharded_store_keeps_an_image_in_one_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::with_layout(dir.path(), Layout::Sharded);
+
+        store
+            .put("7.jpg", Bytes::from_static(b"original"))
+            .await
+            .unwrap();
+        store
+            .put("7_files/9/0_0.jpg", Bytes::from_static(b"tile"))
+            .await
+            .unwrap();
+        store
+            .put("8.jpg", Bytes::from_static(b"other"))
+            .await
+            .unwrap();
+
+        let shard = dir.path().join(shard("7.jpg"));
+        assert!(shard.join("7.jpg").is_file());
+        assert!(shard.join("7_files/9/0_0.jpg").is_file());
+        assert!(!dir.path().join("7.jpg").exists());
+        assert_eq!(store.list("7_").await.unwrap(), vec!["7_files/9/0_0.jpg"]);
+        assert_eq!(store.list("").await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn migration_moves_flat_blobs_and_can_be_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let flat = LocalBlobStore::new(dir.path());
+        flat.put("7.jpg", Bytes::from_static(b"original"))
+            .await
+            .unwrap();
+        flat.put("7_files/9/0_0.jpg", Bytes::from_static(b"tile"))
+            .await
+            .unwrap();
+        flat.put("8.jpg", Bytes::from_static(b"stale"))
+            .await
+            .unwrap();
+        // A sharded server rewrote 8.jpg before the migration got to it.
+        let shard_8 = dir.path().join(shard("8.jpg"));
+        std::fs::create_dir_all(&shard_8).unwrap();
+        std::fs::write(shard_8.join("8.jpg"), b"fresh").unwrap();
+
+        let dry_run = migrate_to_sharded(dir.path(), true).unwrap();
+        assert_eq!(
+            dry_run,
+            MigrationReport {
+                moved: 2,
+                superseded: 1
+            }
+        );
+        assert!(dir.path().join("7.jpg").is_file());
+
+        // The flat store still finds blobs while they move.
+        std::fs::create_dir_all(dir.path().join(shard("7.jpg"))).unwrap();
+        std::fs::rename(
+            dir.path().join("7.jpg"),
+            dir.path().join(shard("7.jpg")).join("7.jpg"),
+        )
+        .unwrap();
+        assert_eq!(flat.get_bytes("7.jpg").await.unwrap().unwrap(), b"original");
+
+        let report = migrate_to_sharded(dir.path(), false).unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                moved: 1,
+                superseded: 1
+            }
+        );
+        assert_eq!(
+            migrate_to_sharded(dir.path(), false).unwrap(),
+            MigrationReport::default()
+        );
+        assert!(!dir.path().join("7_files").exists());
+
+        let sharded = LocalBlobStore::with_layout(dir.path(), Layout::Sharded);
+        assert_eq!(sharded.get_bytes("8.jpg").await.unwrap().unwrap(), b"fresh");
+        assert_eq!(
+            sharded.list("7").await.unwrap(),
+            vec!["7.jpg", "7_files/9/0_0.jpg"]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::{stream, StreamExt};
+
+use crate::storage::{validate_key, BlobMetadata, BlobStore, BlobStream};
+
+/// Keeps blobs in memory; for tests.
+#[derive(Debug, Default)]
+pub struct MemoryBlobStore {
+    blobs: Mutex<BTreeMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        validate_key(key)?;
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (data, SystemTime::now()));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<BlobStream>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .get(key)
+            .map(|(data, _)| stream::iter([Ok(data.clone())]).boxed()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.blobs.lock().unwrap().contains_key(key))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<BlobMetadata>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs.get(key).map(|(data, last_modified)| BlobMetadata {
+            len: data.len() as u64,
+            last_modified: *last_modified,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_blobs() {
+        let store = MemoryBlobStore::new();
+        store
+            .put("1.jpg", Bytes::from_static(b"abc"))
+            .await
+            .unwrap();
+        store.put("2.jpg", Bytes::from_static(b"de")).await.unwrap();
+
+        assert_eq!(store.get_bytes("1.jpg").await.unwrap().unwrap(), b"abc");
+        assert_eq!(store.list("").await.unwrap(), vec!["1.jpg", "2.jpg"]);
+        assert_eq!(store.metadata("2.jpg").await.unwrap().unwrap().len, 2);
+
+        store.delete("1.jpg").await.unwrap();
+        assert!(!store.exists("1.jpg").await.unwrap());
+        assert!(store.get_bytes("1.jpg").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,158 @@
+pub mod local;
+#[cfg(test)]
+pub mod memory;
+pub mod s3;
+pub mod sqlite;
+
+use std::ops::Range;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use tempfile::TempPath;
+
+/// A stream of blob contents.
+pub type BlobStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Size and modification time of a stored blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobMetadata {
+    pub len: u64,
+    pub last_modified: SystemTime,
+}
+
+/// Where originals, thumbnail variants and tiles are kept.
+///
+/// Keys are relative, `/`-separated paths such as `7.jpg` or
+/// `7_files/9/0_0.jpg`; `..` and absolute keys are rejected.
+#[async_trait]
+pub trait BlobStore: Send + Sync + 'static {
+    /// Stores `data` under `key`, replacing any previous blob. Readers never
+    /// observe a partially written blob.
+    async fn put(&self, key: &str, data: Bytes) -> Result<()>;
+
+    /// Stores the contents of a temporary file under `key`, like `put`. The
+    /// default reads the file into memory; backends that can move or stream
+    /// it should override this.
+    async fn put_file(&self, key: &str, file: TempPath) -> Result<()> {
+        let data = tokio::fs::read(&file).await?;
+        self.put(key, data.into()).await
+    }
+
+    /// Streams the blob, or returns `None` if it does not exist.
+    async fn get(&self, key: &str) -> Result<Option<BlobStream>>;
+
+    /// Removes the blob; deleting a missing blob is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Keys starting with `prefix`, in lexicographic order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    async fn metadata(&self, key: &str) -> Result<Option<BlobMetadata>>;
+
+    /// Streams the bytes in `range`. The default skips through `get`;
+    /// backends that can seek should override it.
+    async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Option<BlobStream>> {
+        let Some(stream) = self.get(key).await? else {
+            return Ok(None);
+        };
+        Ok(Some(slice_stream(stream, range)))
+    }
+
+    /// Reads a whole blob into memory.
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(stream) = self.get(key).await? else {
+            return Ok(None);
+        };
+        let data = stream
+            .try_fold(Vec::new(), |mut data, chunk| async move {
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            })
+            .await?;
+        Ok(Some(data))
+    }
+}
+
+/// Gives handlers access to the configured blob store.
+pub trait BlobStoreProvider: Send + Sync + 'static {
+    fn blob_store(&self) -> &dyn BlobStore;
+}
+
+/// Key of the original upload of an image.
+pub fn original_key(id: i64) -> String {
+    format!("{id}.jpg")
+}
+
+/// Prefix shared by the keys of all variants and tiles of an image.
+pub fn derived_key_prefix(id: i64) -> String {
+    format!("{id}_")
+}
+
+/// Rejects keys that could escape the store's root.
+pub fn validate_key(key: &str) -> Result<()> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && !key.contains('\\')
+        && key
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    if !valid {
+        anyhow::bail!("invalid blob key `{key}`");
+    }
+    Ok(())
+}
+
+// Cuts `range` out of a stream of chunks.
+fn slice_stream(stream: BlobStream, range: Range<u64>) -> BlobStream {
+    let mut offset = 0u64;
+    stream
+        .try_filter_map(move |chunk| {
+            let start = offset;
+            let end = start + chunk.len() as u64;
+            offset = end;
+            let slice = (start < range.end && end > range.start).then(|| {
+                let from = range.start.saturating_sub(start) as usize;
+                let to = (range.end.min(end) - start) as usize;
+                chunk.slice(from..to)
+            });
+            async move { Ok(slice) }
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+
+    #[test]
+    fn rejects_escaping_keys() {
+        assert!(validate_key("7.jpg").is_ok());
+        assert!(validate_key("7_files/9/0_0.jpg").is_ok());
+        assert!(validate_key("../7.jpg").is_err());
+        assert!(validate_key("/etc/passwd").is_err());
+        assert!(validate_key("a//b").is_err());
+        assert!(validate_key("").is_err());
+    }
+
+    #[tokio::test]
+    async fn slices_across_chunks() {
+        let chunks: Vec<std::io::Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"0123")),
+            Ok(Bytes::from_static(b"4567")),
+            Ok(Bytes::from_static(b"89")),
+        ];
+        let sliced: Vec<Bytes> = slice_stream(stream::iter(chunks).boxed(), 2..9)
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(sliced.concat(), b"2345678");
+    }
+}
@@ -0,0 +1,364 @@
+// Blob store inside the SQLite database itself, so a single-file deployment
+// can be backed up by copying `images.db`.
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::StreamExt;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use tempfile::TempPath;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_blocking;
+
+use crate::storage::{validate_key, BlobMetadata, BlobStore, BlobStream};
+
+// Size of the pieces blobs are read and written in.
+const CHUNK_SIZE: usize = 64 * 1024;
+// Chunks buffered ahead of a slow client.
+const READ_AHEAD: usize = 4;
+// How long to wait for the sqlx pool (or another stream) to release a lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stores blobs in the `blobs` table, reading and writing them with SQLite's
+/// incremental BLOB I/O so large originals are never bound as one parameter
+/// or loaded whole to serve a range.
+///
+/// The table is created by the server's migrations.
+#[derive(Debug, Clone)]
+pub struct SqliteBlobStore {
+    path: PathBuf,
+}
+
+impl SqliteBlobStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Each operation gets its own connection: streams hold theirs for as long
+    // as the client reads, and opening a SQLite file is cheap. The read
+    // transaction of a stream only leaves writers alone in WAL mode, so that
+    // is switched on here as well as for the sqlx pool.
+    fn connect(path: &Path) -> Result<Connection> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("failed to open blob database {}", path.display()))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        Ok(connection)
+    }
+
+    async fn with_connection<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+    {
+        let path = self.path.clone();
+        spawn_blocking(move || f(&mut Self::connect(&path)?)).await?
+    }
+
+    // Streams `range` (or the whole blob) from a blocking task that keeps
+    // the BLOB handle open while the receiver pulls chunks.
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<Option<BlobStream>> {
+        validate_key(key)?;
+        let path = self.path.clone();
+        let key = key.to_string();
+        let (found_tx, found_rx) = oneshot::channel();
+        let (chunk_tx, chunk_rx) = mpsc::channel(READ_AHEAD);
+
+        spawn_blocking(move || {
+            let opened = Self::connect(&path).and_then(|connection| {
+                let id = connection
+                    .query_row("SELECT id FROM blobs WHERE key = ?1", [&key], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .optional()?;
+                Ok((connection, id))
+            });
+            let (connection, id) = match opened {
+                Ok((connection, Some(id))) => (connection, id),
+                Ok((_, None)) => {
+                    let _ = found_tx.send(Ok(false));
+                    return;
+                }
+                Err(e) => {
+                    let _ = found_tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut blob = match connection.blob_open(DatabaseName::Main, "blobs", "data", id, true)
+            {
+                Ok(blob) => blob,
+                Err(e) => {
+                    let _ = found_tx.send(Err(e.into()));
+                    return;
+                }
+            };
+            let _ = found_tx.send(Ok(true));
+
+            let len = blob.len() as u64;
+            let range = range.unwrap_or(0..len);
+            let (start, end) = (range.start.min(len), range.end.min(len));
+            if let Err(e) = blob.seek(SeekFrom::Start(start)) {
+                let _ = chunk_tx.blocking_send(Err(e));
+                return;
+            }
+            let mut remaining = end - start;
+            while remaining > 0 {
+                let mut chunk = vec![0; remaining.min(CHUNK_SIZE as u64) as usize];
+                let result = blob.read_exact(&mut chunk).map(|()| Bytes::from(chunk));
+                let failed = result.is_err();
+                remaining = remaining.saturating_sub(CHUNK_SIZE as u64);
+                // The client went away.
+                if chunk_tx.blocking_send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        if !found_rx.await?? {
+            return Ok(None);
+        }
+        let stream = futures::stream::unfold(chunk_rx, |mut chunk_rx| async move {
+            let chunk = chunk_rx.recv().await?;
+            Some((chunk, chunk_rx))
+        });
+        Ok(Some(stream.boxed()))
+    }
+}
+
+#[async_trait]
+impl BlobStore for SqliteBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        validate_key(key)?;
+        let owned_key = key.to_string();
+        self.with_connection(move |connection| {
+            write_blob(connection, &owned_key, data.len(), &data[..])
+        })
+        .await
+        .with_context(|| format!("failed to store blob `{key}`"))
+    }
+
+    async fn put_file(&self, key: &str, file: TempPath) -> Result<()> {
+        validate_key(key)?;
+        let owned_key = key.to_string();
+        self.with_connection(move |connection| {
+            let reader = std::fs::File::open(&file)?;
+            let len = reader.metadata()?.len() as usize;
+            write_blob(connection, &owned_key, len, reader)
+        })
+        .await
+        .with_context(|| format!("failed to store blob `{key}`"))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<BlobStream>> {
+        self.stream(key, None).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        validate_key(key)?;
+        let key = key.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM blobs WHERE key = ?1", [&key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.metadata(key).await?.is_some())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = prefix.to_string();
+        self.with_connection(move |connection| {
+            // `substr` rather than LIKE, which would treat `_` as a wildcard.
+            let mut statement = connection.prepare(
+                "SELECT key FROM blobs WHERE substr(key, 1, length(?1)) = ?1 ORDER BY key",
+            )?;
+            let keys = statement
+                .query_map([&prefix], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<BlobMetadata>> {
+        validate_key(key)?;
+        let key = key.to_string();
+        self.with_connection(move |connection| {
+            let metadata = connection
+                .query_row(
+                    "SELECT len, last_modified FROM blobs WHERE key = ?1",
+                    [&key],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()?
+                .map(|(len, millis)| BlobMetadata {
+                    len: len as u64,
+                    last_modified: SystemTime::UNIX_EPOCH
+                        + Duration::from_millis(millis.max(0) as u64),
+                });
+            Ok(metadata)
+        })
+        .await
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Option<BlobStream>> {
+        self.stream(key, Some(range)).await
+    }
+}
+
+// Writes `len` bytes from `reader` in chunks; readers only see the new blob
+// once it is completely written.
+fn write_blob(
+    connection: &mut Connection,
+    key: &str,
+    len: usize,
+    mut reader: impl Read,
+) -> Result<()> {
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "INSERT INTO blobs (key, data, len, last_modified)
+         VALUES (?1, zeroblob(?2), ?2, ?3)
+         ON CONFLICT (key) DO UPDATE SET
+             data = excluded.data,
+             len = excluded.len,
+             last_modified = excluded.last_modified",
+        params![key, len as i64, unix_millis(SystemTime::now())],
+    )?;
+    let id: i64 = transaction.query_row("SELECT id FROM blobs WHERE key = ?1", [key], |row| {
+        row.get(0)
+    })?;
+    {
+        let mut blob = transaction.blob_open(DatabaseName::Main, "blobs", "data", id, false)?;
+        let mut chunk = vec![0; CHUNK_SIZE.min(len)];
+        let mut offset = 0;
+        while offset < len {
+            let chunk = &mut chunk[..(len - offset).min(CHUNK_SIZE)];
+            reader.read_exact(chunk)?;
+            blob.write_all_at(chunk, offset)?;
+            offset += chunk.len();
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::TryStreamExt;
+
+    fn store(dir: &tempfile::TempDir) -> SqliteBlobStore {
+        let path = dir.path().join("images.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(include_str!(
+                "../../migrations/20261018110000_add_blobs.sql"
+            ))
+            .unwrap();
+        SqliteBlobStore::new(path)
+    }
+
+    #[tokio::test]
+    async fn stores_replaces_and_lists_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+
+        store
+            .put("7_files/9/0_0.jpg", Bytes::from_static(b"tile"))
+            .await
+            .unwrap();
+        store
+            .put("7.jpg", Bytes::from_static(b"old"))
+            .await
+            .unwrap();
+        store
+            .put("7.jpg", Bytes::from_static(b"original"))
+            .await
+            .unwrap();
+        store.put("70.jpg", Bytes::new()).await.unwrap();
+
+        assert_eq!(
+            store.get_bytes("7.jpg").await.unwrap().unwrap(),
+            b"original"
+        );
+        assert_eq!(store.metadata("7.jpg").await.unwrap().unwrap().len, 8);
+        assert_eq!(store.get_bytes("70.jpg").await.unwrap().unwrap(), b"");
+        assert_eq!(store.list("7_").await.unwrap(), vec!["7_files/9/0_0.jpg"]);
+        assert_eq!(store.list("7").await.unwrap().len(), 3);
+        assert!(store.put("../escape", Bytes::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_large_blobs_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        store.put("big", Bytes::from(data.clone())).await.unwrap();
+
+        let chunks: Vec<Bytes> = store
+            .get("big")
+            .await
+            .unwrap()
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), data);
+
+        let start = CHUNK_SIZE as u64 - 2;
+        let range: Vec<Bytes> = store
+            .get_range("big", start..start + 5)
+            .await
+            .unwrap()
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(range.concat(), &data[start as usize..start as usize + 5]);
+    }
+
+    #[tokio::test]
+    async fn open_streams_do_not_block_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        let data = vec![1; CHUNK_SIZE * (READ_AHEAD + 4)];
+        store.put("big", Bytes::from(data)).await.unwrap();
+
+        // A slow client: the stream stays open with most of the blob unread.
+        let mut stream = store.get("big").await.unwrap().unwrap();
+        stream.try_next().await.unwrap().unwrap();
+
+        let put = store.put("other", Bytes::from_static(b"new"));
+        tokio::time::timeout(BUSY_TIMEOUT / 2, put)
+            .await
+            .expect("writer waited for the reader")
+            .unwrap();
+        let rest: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(rest.concat().len(), CHUNK_SIZE * (READ_AHEAD + 3));
+    }
+
+    #[tokio::test]
+    async fn missing_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+
+        assert!(store.get("a").await.unwrap().is_none());
+        assert!(store.get_range("a", 0..1).await.unwrap().is_none());
+        assert!(!store.exists("a").await.unwrap());
+        store.delete("a").await.unwrap();
+    }
+}
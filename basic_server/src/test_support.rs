@@ -0,0 +1,38 @@
+// Fixtures shared by the tests of several modules.
+use std::sync::Arc;
+
+use sqlx::sqlite::SqlitePoolOptions;
+
+use crate::config::Config;
+use crate::repository::image_repository::NewImage;
+use crate::storage::memory::MemoryBlobStore;
+use crate::storage::BlobStore;
+use crate::AppState;
+
+/// State on a fresh, migrated in-memory database, with blobs kept in memory.
+pub async fn app_state(config: Config) -> Arc<AppState> {
+    app_state_with_store(config, Arc::new(MemoryBlobStore::new())).await
+}
+
+/// Like [`app_state`], with blobs kept in `store`.
+pub async fn app_state_with_store(config: Config, store: Arc<dyn BlobStore>) -> Arc<AppState> {
+    // One connection, as every connection to `:memory:` is its own database.
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    AppState::new(pool, config, store)
+}
+
+/// An upload of `mime_type` without tags, file name, checksum or size.
+pub fn new_image(mime_type: &str) -> NewImage {
+    NewImage {
+        tags: String::new(),
+        mime_type: mime_type.to_string(),
+        file_name: None,
+        sha256: None,
+        size: None,
+    }
+}
@@ -0,0 +1,393 @@
+// Contact sheets: many images laid out on one canvas, for email digests and
+// CSS sprites. Callers get the encoded sheet plus where every image ended up.
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::{Thumbnail, ThumbnailError, ThumbnailFormat};
+
+/// Text rendered below each cell.
+#[derive(Debug, Clone)]
+pub struct CaptionStyle {
+    /// TrueType/OpenType font data.
+    pub font: Vec<u8>,
+    /// Font size in pixels.
+    pub size: f32,
+    pub color: [u8; 4],
+}
+
+/// Grid layout and look of the sheet.
+#[derive(Debug, Clone)]
+pub struct ContactSheetOptions {
+    pub columns: u32,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    pub padding: u32,
+    pub background: [u8; 4],
+    pub captions: Option<CaptionStyle>,
+    pub format: ThumbnailFormat,
+}
+
+impl ContactSheetOptions {
+    /// Width and height of a sheet of `count` images, known before any image
+    /// is decoded.
+    pub fn sheet_size(&self, count: usize) -> (u32, u32) {
+        let count = count.max(1) as u32;
+        let columns = self.columns.clamp(1, count);
+        let rows = count.div_ceil(columns);
+        let width = self.padding + columns * (self.cell_width + self.padding);
+        let height = self.padding + rows * self.row_pitch();
+        (width, height)
+    }
+
+    fn caption_height(&self) -> u32 {
+        self.captions
+            .as_ref()
+            .map_or(0, |style| (style.size * 1.5).ceil() as u32)
+    }
+
+    fn row_pitch(&self) -> u32 {
+        self.cell_height + self.caption_height() + self.padding
+    }
+}
+
+impl Default for ContactSheetOptions {
+    fn default() -> Self {
+        Self {
+            columns: 4,
+            cell_width: 160,
+            cell_height: 120,
+            padding: 8,
+            background: [255, 255, 255, 255],
+            captions: None,
+            format: ThumbnailFormat::Png,
+        }
+    }
+}
+
+/// Where the image of an entry comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntrySource {
+    Path(PathBuf),
+    /// An encoded image already in memory.
+    Encoded(Vec<u8>),
+    /// An image already scaled with [`fit_to_cell`], so callers can decode
+    /// one image at a time instead of holding every original.
+    Scaled(RgbaImage),
+}
+
+/// One image to place on the sheet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactSheetEntry {
+    pub source: EntrySource,
+    pub caption: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where an entry was placed: its grid cell and the scaled image inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SheetCell {
+    pub index: usize,
+    pub cell: Rect,
+    pub image: Rect,
+}
+
+/// The encoded sheet and the coordinates of every entry, in input order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactSheet {
+    pub width: u32,
+    pub height: u32,
+    pub format: ThumbnailFormat,
+    pub data: Vec<u8>,
+    pub cells: Vec<SheetCell>,
+}
+
+/// Lays out `entries` row by row and encodes the result.
+pub fn compose(
+    entries: &[ContactSheetEntry],
+    options: &ContactSheetOptions,
+) -> anyhow::Result<ContactSheet> {
+    if entries.is_empty() {
+        return Err(ThumbnailError::Processing(
+            "contact sheet needs at least one image".to_string(),
+        )
+        .into());
+    }
+
+    let font = match &options.captions {
+        Some(style) => Some(
+            FontVec::try_from_vec(style.font.clone())
+                .map_err(|e| ThumbnailError::Processing(format!("invalid caption font: {e}")))?,
+        ),
+        None => None,
+    };
+    let caption_height = options.caption_height();
+
+    let columns = options.columns.clamp(1, entries.len() as u32);
+    let pitch_x = options.cell_width + options.padding;
+    let pitch_y = options.row_pitch();
+    let (width, height) = options.sheet_size(entries.len());
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba(options.background));
+    let mut cells = Vec::with_capacity(entries.len());
+
+    for (index, entry) in entries.iter().enumerate() {
+        let column = index as u32 % columns;
+        let row = index as u32 / columns;
+        let cell = Rect {
+            x: options.padding + column * pitch_x,
+            y: options.padding + row * pitch_y,
+            width: options.cell_width,
+            height: options.cell_height,
+        };
+
+        let image = match &entry.source {
+            EntrySource::Path(path) => Cow::Owned(scale(
+                &Thumbnail::load_image(path)?,
+                cell.width,
+                cell.height,
+            )),
+            EntrySource::Encoded(data) => Cow::Owned(fit_to_cell(data, cell.width, cell.height)?),
+            EntrySource::Scaled(image) => Cow::Borrowed(image),
+        };
+        let placed = Rect {
+            x: cell.x + (cell.width - image.width().min(cell.width)) / 2,
+            y: cell.y + (cell.height - image.height().min(cell.height)) / 2,
+            width: image.width(),
+            height: image.height(),
+        };
+        image::imageops::overlay(
+            &mut canvas,
+            image.as_ref(),
+            placed.x as i64,
+            placed.y as i64,
+        );
+
+        if let (Some(font), Some(style), Some(caption)) = (&font, &options.captions, &entry.caption)
+        {
+            let area = Rect {
+                x: cell.x,
+                y: cell.y + cell.height,
+                width: cell.width,
+                height: caption_height,
+            };
+            draw_caption(&mut canvas, font, style, caption, area);
+        }
+
+        cells.push(SheetCell {
+            index,
+            cell,
+            image: placed,
+        });
+    }
+
+    let data = Thumbnail::encode_to_vec(&DynamicImage::ImageRgba8(canvas), options.format)?;
+    Ok(ContactSheet {
+        width,
+        height,
+        format: options.format,
+        data,
+        cells,
+    })
+}
+
+/// Decodes `data` and scales it to fit a `width` x `height` cell, keeping its
+/// aspect ratio.
+pub fn fit_to_cell(data: &[u8], width: u32, height: u32) -> anyhow::Result<RgbaImage> {
+    Ok(scale(&Thumbnail::decode_image(data)?, width, height))
+}
+
+fn scale(image: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+    image.thumbnail(width, height).to_rgba8()
+}
+
+// Draws `text` centred in `area`, cutting it off where it no longer fits.
+fn draw_caption(
+    canvas: &mut RgbaImage,
+    font: &FontVec,
+    style: &CaptionStyle,
+    text: &str,
+    area: Rect,
+) {
+    let scaled = font.as_scaled(PxScale::from(style.size));
+
+    let mut glyphs = Vec::new();
+    let mut caret = 0.0f32;
+    let mut previous = None;
+    for ch in text.chars() {
+        let id = scaled.glyph_id(ch);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let advance = scaled.h_advance(id);
+        if caret + advance > area.width as f32 {
+            break;
+        }
+        glyphs
+            .push(id.with_scale_and_position(style.size, ab_glyph::point(caret, scaled.ascent())));
+        caret += advance;
+        previous = Some(id);
+    }
+
+    let offset_x = area.x as f32 + (area.width as f32 - caret) / 2.0;
+    let offset_y = area.y as f32 + (area.height as f32 - scaled.height()) / 2.0;
+    let [red, green, blue, alpha] = style.color;
+
+    for glyph in glyphs {
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let px = (offset_x + bounds.min.x) as i64 + x as i64;
+            let py = (offset_y + bounds.min.y) as i64 + y as i64;
+            if px < 0 || py < 0 || px >= canvas.width() as i64 || py >= canvas.height() as i64 {
+                return;
+            }
+            let pixel = canvas.get_pixel_mut(px as u32, py as u32);
+            let weight = coverage * alpha as f32 / 255.0;
+            for (channel, value) in pixel.0.iter_mut().zip([red, green, blue]) {
+                *channel = (*channel as f32 * (1.0 - weight) + value as f32 * weight).round() as u8;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+    use tempfile::tempdir;
+
+    fn entries(dir: &std::path::Path, sizes: &[(u32, u32)]) -> Vec<ContactSheetEntry> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(index, (width, height))| {
+                let path = dir.join(format!("{index}.png"));
+                RgbImage::from_pixel(*width, *height, Rgb([0, 0, 255]))
+                    .save(&path)
+                    .unwrap();
+                ContactSheetEntry {
+                    source: EntrySource::Path(path),
+                    caption: Some(format!("image {index}")),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lays_out_cells_in_rows_with_padding() {
+        let dir = tempdir().unwrap();
+        let entries = entries(dir.path(), &[(320, 240), (100, 200), (50, 50)]);
+        let options = ContactSheetOptions {
+            columns: 2,
+            cell_width: 100,
+            cell_height: 100,
+            padding: 10,
+            ..ContactSheetOptions::default()
+        };
+
+        let sheet = compose(&entries, &options).unwrap();
+
+        assert_eq!((sheet.width, sheet.height), (230, 230));
+        assert_eq!(
+            sheet.cells[1].cell,
+            Rect {
+                x: 120,
+                y: 10,
+                width: 100,
+                height: 100
+            }
+        );
+        assert_eq!(
+            sheet.cells[2].cell,
+            Rect {
+                x: 10,
+                y: 120,
+                width: 100,
+                height: 100
+            }
+        );
+        // Images keep their aspect ratio and are centred in their cell.
+        assert_eq!(
+            sheet.cells[0].image,
+            Rect {
+                x: 10,
+                y: 22,
+                width: 100,
+                height: 75
+            }
+        );
+        assert_eq!(
+            sheet.cells[1].image,
+            Rect {
+                x: 145,
+                y: 10,
+                width: 50,
+                height: 100
+            }
+        );
+
+        let decoded = image::load_from_memory(&sheet.data).unwrap().to_rgba8();
+        assert_eq!(decoded.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert_eq!(decoded.get_pixel(60, 60).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn scaled_entries_are_placed_like_decoded_ones() {
+        let dir = tempdir().unwrap();
+        let decoded = entries(dir.path(), &[(320, 240), (100, 200)]);
+        let scaled: Vec<_> = decoded
+            .iter()
+            .map(|entry| {
+                let EntrySource::Path(path) = &entry.source else {
+                    unreachable!()
+                };
+                let data = std::fs::read(path).unwrap();
+                ContactSheetEntry {
+                    source: EntrySource::Scaled(fit_to_cell(&data, 100, 100).unwrap()),
+                    caption: None,
+                }
+            })
+            .collect();
+        let options = ContactSheetOptions {
+            columns: 1,
+            cell_width: 100,
+            cell_height: 100,
+            ..ContactSheetOptions::default()
+        };
+
+        let expected = compose(&decoded, &options).unwrap();
+        let sheet = compose(&scaled, &options).unwrap();
+
+        assert_eq!(sheet.cells, expected.cells);
+        assert_eq!((sheet.width, sheet.height), options.sheet_size(2));
+    }
+
+    #[test]
+    fn rejects_invalid_caption_font() {
+        let dir = tempdir().unwrap();
+        let entries = entries(dir.path(), &[(10, 10)]);
+        let options = ContactSheetOptions {
+            captions: Some(CaptionStyle {
+                font: b"not a font".to_vec(),
+                size: 12.0,
+                color: [0, 0, 0, 255],
+            }),
+            ..ContactSheetOptions::default()
+        };
+
+        assert!(compose(&entries, &options).is_err());
+    }
+}
@@ -1,9 +1,24 @@
 // Imports necessary libraries for file handling, I/O operations, and formatting.
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
 
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType as ResizeFilter;
+use image::DynamicImage;
+
+pub mod contact_sheet;
+pub mod optimize;
+pub mod quantize;
+pub mod tiles;
+
+use crate::optimize::{count_colors, OptimizeOptions};
+use crate::quantize::{QuantizeOptions, QuantizedImage};
+use crate::tiles::{TileOptions, TilePyramid};
+
 // Defines a custom enum for thumbnail-related errors with two variants to handle
 // different types of errors: file not found and errors during processing.
 #[derive(Debug)]
@@ -25,6 +40,158 @@ impl Display for ThumbnailError {
 // Implements the standard Error trait for ThumbnailError to support error handling in Rust.
 impl std::error::Error for ThumbnailError {}
 
+/// Version of the thumbnail generator. Bumped whenever a change makes newly
+/// generated thumbnails differ from earlier ones, so callers can tell which
+/// stored thumbnails are outdated.
+pub const GENERATOR_VERSION: u32 = 1;
+
+// Quality used for variants when none is given.
+const DEFAULT_VARIANT_QUALITY: u8 = 80;
+// Requested qualities are snapped to this step so near-identical values share a cache entry.
+const QUALITY_STEP: u8 = 5;
+
+/// Output encodings a thumbnail can be written in.
+///
+/// `Avif` is only available when the crate is built with the `avif` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    WebP,
+    Png,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+impl ThumbnailFormat {
+    /// All formats this build can encode, ordered from most to least preferred.
+    pub fn supported() -> &'static [ThumbnailFormat] {
+        &[
+            #[cfg(feature = "avif")]
+            ThumbnailFormat::Avif,
+            ThumbnailFormat::WebP,
+            ThumbnailFormat::Jpeg,
+            ThumbnailFormat::Png,
+        ]
+    }
+
+    /// File extension used when the format is written to disk.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::WebP => "webp",
+            ThumbnailFormat::Png => "png",
+            #[cfg(feature = "avif")]
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+
+    /// MIME type to send as `Content-Type`.
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::WebP => "image/webp",
+            ThumbnailFormat::Png => "image/png",
+            #[cfg(feature = "avif")]
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Clamps a quality to 1..=100 and snaps it to a coarse step. Lossless
+    /// formats ignore quality, so they always normalize to 100.
+    pub fn normalize_quality(&self, quality: Option<u8>) -> u8 {
+        if matches!(self, ThumbnailFormat::Png | ThumbnailFormat::WebP) {
+            return 100;
+        }
+        let quality = quality.unwrap_or(DEFAULT_VARIANT_QUALITY).clamp(1, 100);
+        let snapped = (quality + QUALITY_STEP / 2) / QUALITY_STEP * QUALITY_STEP;
+        snapped.clamp(QUALITY_STEP, 100)
+    }
+
+    /// Looks up a supported format by file extension, e.g. from a tile URL.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.to_ascii_lowercase();
+        let extension = if extension == "jpeg" {
+            "jpg"
+        } else {
+            extension.as_str()
+        };
+        Self::supported()
+            .iter()
+            .copied()
+            .find(|format| format.extension() == extension)
+    }
+
+    /// Looks up a supported format by MIME type, e.g. from an `Accept` header.
+    pub fn from_mime_type(mime: &str) -> Option<Self> {
+        Self::supported()
+            .iter()
+            .copied()
+            .find(|format| format.mime_type().eq_ignore_ascii_case(mime))
+    }
+}
+
+/// How an image is fitted into the requested box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Fit {
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Scale to cover the box, keeping the aspect ratio, and crop the overflow.
+    Cover,
+    /// Stretch to exactly the box size.
+    Fill,
+}
+
+impl Fit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+}
+
+/// Size, fit, encoding and quality of a thumbnail variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThumbnailOptions {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    pub format: ThumbnailFormat,
+    /// Encoder quality from 1 to 100. Ignored by the lossless formats (PNG, WebP).
+    pub quality: u8,
+}
+
+impl Default for ThumbnailOptions {
+    // Matches the output of `Thumbnail::make_thumbnail`.
+    fn default() -> Self {
+        Self {
+            width: 100,
+            height: 100,
+            fit: Fit::Contain,
+            format: ThumbnailFormat::Jpeg,
+            quality: 75,
+        }
+    }
+}
+
+/// An encoded thumbnail held in memory together with the format it was encoded as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedThumbnail {
+    pub format: ThumbnailFormat,
+    pub data: Vec<u8>,
+}
+
 // Defines the Thumbnail struct. Currently, this struct does not encapsulate any data
 // and serves as a namespace for the thumbnail creation functionality.
 pub struct Thumbnail {}
@@ -44,20 +211,195 @@ impl Thumbnail {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
     /// use std::path::PathBuf;
     ///
     /// fn main() -> anyhow::Result<()> {
     ///     let source_path = PathBuf::from("path/to/source/image.jpg");
     ///     let thumbnail_path = PathBuf::from("path/to/save/thumbnail.jpg");
-    ///     super::Thumbnail::make_thumbnail(&source_path, &thumbnail_path)?;
+    ///     thumbnail::Thumbnail::make_thumbnail(&source_path, &thumbnail_path)?;
     ///     Ok(())
     /// }
     /// ```
     pub fn make_thumbnail<P: AsRef<Path>>(file_path: P, thumbnail_path: P) -> anyhow::Result<()> {
-        let mut file = File::open(file_path.as_ref()).map_err(|e| {
+        let image = Self::load_image(file_path.as_ref())?;
+
+        let thumbnail = image.thumbnail(100, 100);
+        thumbnail.save(thumbnail_path.as_ref())?;
+
+        Ok(())
+    }
+
+    /// Creates a thumbnail of an image file and encodes it as `format`,
+    /// regardless of the extension of `thumbnail_path`.
+    pub fn make_thumbnail_as<P: AsRef<Path>>(
+        file_path: P,
+        thumbnail_path: P,
+        format: ThumbnailFormat,
+    ) -> anyhow::Result<()> {
+        let image = Self::load_image(file_path.as_ref())?;
+
+        let thumbnail = image.thumbnail(100, 100);
+        let file = File::create(thumbnail_path.as_ref())?;
+        let mut writer = BufWriter::new(file);
+        Self::encode(&thumbnail, format, &mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Creates a thumbnail variant of an image file as described by `options`
+    /// and returns the encoded bytes.
+    pub fn make_variant<P: AsRef<Path>>(
+        file_path: P,
+        options: &ThumbnailOptions,
+    ) -> anyhow::Result<EncodedThumbnail> {
+        let image = Self::load_image(file_path.as_ref())?;
+        Self::render_variant(&image, options)
+    }
+
+    /// Creates several variants of one image file, decoding the source only once.
+    pub fn make_variants<P: AsRef<Path>>(
+        file_path: P,
+        options: &[ThumbnailOptions],
+    ) -> anyhow::Result<Vec<EncodedThumbnail>> {
+        let image = Self::load_image(file_path.as_ref())?;
+        options
+            .iter()
+            .map(|options| Self::render_variant(&image, options))
+            .collect()
+    }
+
+    /// Like [`Thumbnail::make_variants`], for an encoded image already in memory,
+    /// e.g. one fetched from a remote blob store.
+    pub fn make_variants_from_memory(
+        data: &[u8],
+        options: &[ThumbnailOptions],
+    ) -> anyhow::Result<Vec<EncodedThumbnail>> {
+        let image = Self::decode_image(data)?;
+        options
+            .iter()
+            .map(|options| Self::render_variant(&image, options))
+            .collect()
+    }
+
+    fn render_variant(
+        image: &DynamicImage,
+        options: &ThumbnailOptions,
+    ) -> anyhow::Result<EncodedThumbnail> {
+        let resized = Self::resize(image, options);
+        let mut data = Vec::new();
+        Self::encode_with_quality(&resized, options.format, Some(options.quality), &mut data)?;
+        Ok(EncodedThumbnail {
+            format: options.format,
+            data,
+        })
+    }
+
+    // Scales an image to the size and fit of `options`.
+    fn resize(image: &DynamicImage, options: &ThumbnailOptions) -> DynamicImage {
+        let (width, height) = (options.width.max(1), options.height.max(1));
+        match options.fit {
+            Fit::Contain => image.thumbnail(width, height),
+            Fit::Cover => image.resize_to_fill(width, height, ResizeFilter::Lanczos3),
+            Fit::Fill => image.thumbnail_exact(width, height),
+        }
+    }
+
+    /// Creates a thumbnail as described by `thumbnail` and picks the smallest
+    /// lossless encoding when it has few colours (screenshots, diagrams,
+    /// logos), falling back to `thumbnail.format` for photographic content.
+    ///
+    /// Lossless candidates are PNG (recompressed with oxipng when the `optimize`
+    /// feature is enabled) and lossless WebP.
+    pub fn make_thumbnail_optimized<P: AsRef<Path>>(
+        file_path: P,
+        thumbnail: &ThumbnailOptions,
+        options: &OptimizeOptions,
+    ) -> anyhow::Result<EncodedThumbnail> {
+        let image = Self::load_image(file_path.as_ref())?;
+        let (format, quality) = (thumbnail.format, thumbnail.quality);
+        let thumbnail = Self::resize(&image, thumbnail);
+
+        if format != ThumbnailFormat::Png && count_colors(&thumbnail, options.max_colors).is_none()
+        {
+            let mut data = Vec::new();
+            Self::encode_with_quality(&thumbnail, format, Some(quality), &mut data)?;
+            return Ok(EncodedThumbnail { format, data });
+        }
+
+        let png = EncodedThumbnail {
+            format: ThumbnailFormat::Png,
+            data: Self::encode_png_optimized(&thumbnail, options)?,
+        };
+        if format == ThumbnailFormat::Png {
+            return Ok(png);
+        }
+
+        let webp = EncodedThumbnail {
+            format: ThumbnailFormat::WebP,
+            data: Self::encode_to_vec(&thumbnail, ThumbnailFormat::WebP)?,
+        };
+        Ok(if webp.data.len() < png.data.len() {
+            webp
+        } else {
+            png
+        })
+    }
+
+    /// Creates a thumbnail of the size and fit of `thumbnail` reduced to a
+    /// palette of at most `options.max_colors` colours, written as an 8-bit
+    /// indexed PNG or GIF; `thumbnail.format` is not used. The returned report
+    /// compares the result against a true-colour PNG of the same thumbnail.
+    pub fn make_thumbnail_quantized<P: AsRef<Path>>(
+        file_path: P,
+        thumbnail: &ThumbnailOptions,
+        options: &QuantizeOptions,
+    ) -> anyhow::Result<QuantizedImage> {
+        let image = Self::load_image(file_path.as_ref())?;
+        let thumbnail = Self::resize(&image, thumbnail);
+        quantize::quantize(&thumbnail, options)
+    }
+
+    /// Cuts an image into a Deep Zoom tile pyramid, writing the `.dzi`
+    /// descriptor to `dzi_path` and the tiles next to it in `<name>_files/`.
+    pub fn make_tile_pyramid<P: AsRef<Path>>(
+        file_path: P,
+        dzi_path: P,
+        options: TileOptions,
+    ) -> anyhow::Result<TilePyramid> {
+        tiles::generate_tile_pyramid(file_path.as_ref(), dzi_path.as_ref(), options)
+    }
+
+    #[cfg(feature = "optimize")]
+    fn encode_png_optimized(
+        image: &DynamicImage,
+        options: &OptimizeOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        let png = Self::encode_to_vec(image, ThumbnailFormat::Png)?;
+        optimize::optimize_png(&png, options)
+    }
+
+    #[cfg(not(feature = "optimize"))]
+    fn encode_png_optimized(
+        image: &DynamicImage,
+        _options: &OptimizeOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        Self::encode_to_vec(image, ThumbnailFormat::Png)
+    }
+
+    fn encode_to_vec(image: &DynamicImage, format: ThumbnailFormat) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        Self::encode(image, format, &mut data)?;
+        Ok(data)
+    }
+
+    // Reads and decodes an image, mapping a missing file to ThumbnailError::NotFound
+    // so callers can tell stale records apart from broken images.
+    fn load_image(file_path: &Path) -> anyhow::Result<DynamicImage> {
+        let mut file = File::open(file_path).map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
-                ThumbnailError::NotFound(file_path.as_ref().to_string_lossy().into_owned())
+                ThumbnailError::NotFound(file_path.to_string_lossy().into_owned())
             } else {
                 ThumbnailError::Processing("Error processing file".to_string())
             }
@@ -66,14 +408,58 @@ impl Thumbnail {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        let image = if let Ok(format) = image::guess_format(&buffer) {
-            image::load_from_memory_with_format(&buffer, format)?
+        Self::decode_image(&buffer)
+    }
+
+    fn decode_image(buffer: &[u8]) -> anyhow::Result<DynamicImage> {
+        let image = if let Ok(format) = image::guess_format(buffer) {
+            image::load_from_memory_with_format(buffer, format)?
         } else {
-            image::load_from_memory(&buffer)?
+            image::load_from_memory(buffer)?
         };
 
-        let thumbnail = image.thumbnail(100, 100);
-        thumbnail.save(thumbnail_path.as_ref())?;
+        Ok(image)
+    }
+
+    fn encode<W: Write>(
+        image: &DynamicImage,
+        format: ThumbnailFormat,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        Self::encode_with_quality(image, format, None, writer)
+    }
+
+    // `quality` of None keeps each encoder's own default.
+    fn encode_with_quality<W: Write>(
+        image: &DynamicImage,
+        format: ThumbnailFormat,
+        quality: Option<u8>,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        let quality = quality.map(|q| q.clamp(1, 100));
+        match format {
+            // JPEG has no alpha channel, so flatten before encoding.
+            ThumbnailFormat::Jpeg => image.to_rgb8().write_with_encoder(match quality {
+                Some(quality) => JpegEncoder::new_with_quality(writer, quality),
+                None => JpegEncoder::new(writer),
+            })?,
+            ThumbnailFormat::WebP => image
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(writer))?,
+            ThumbnailFormat::Png => image.write_with_encoder(PngEncoder::new_with_quality(
+                writer,
+                CompressionType::Best,
+                FilterType::Adaptive,
+            ))?,
+            #[cfg(feature = "avif")]
+            ThumbnailFormat::Avif => {
+                use image::codecs::avif::AvifEncoder;
+                image.to_rgba8().write_with_encoder(match quality {
+                    Some(quality) => AvifEncoder::new_with_speed_quality(writer, 4, quality),
+                    None => AvifEncoder::new(writer),
+                })?
+            }
+        }
 
         Ok(())
     }
@@ -83,10 +469,274 @@ impl Thumbnail {
 #[cfg(test)]
 mod tests {
     // Imports all necessary components from the outer module.
-    //use super::*;
+    use super::*;
+
+    use image::{Rgba, RgbaImage};
+    use tempfile::tempdir;
+
+    fn write_source_image(path: &Path) {
+        RgbaImage::from_pixel(300, 200, Rgba([200, 40, 40, 255]))
+            .save(path)
+            .unwrap();
+    }
 
     #[test]
     fn it_works() {
         // Example test function. Should be expanded with actual tests.
     }
+
+    #[test]
+    fn decodes_every_format_uploads_may_use() {
+        let source = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 4, Rgba([1, 2, 3, 255])));
+        let formats = [
+            (image::ImageFormat::Ico, source.clone()),
+            (image::ImageFormat::Qoi, source.clone()),
+            (image::ImageFormat::Pnm, source.to_rgb8().into()),
+            (image::ImageFormat::Farbfeld, source.to_rgba16().into()),
+            (image::ImageFormat::Hdr, source.to_rgb32f().into()),
+            (image::ImageFormat::OpenExr, source.to_rgba32f().into()),
+        ];
+
+        for (format, image) in formats {
+            let mut data = std::io::Cursor::new(Vec::new());
+            image.write_to(&mut data, format).unwrap();
+            let data = data.into_inner();
+
+            assert_eq!(image::guess_format(&data).unwrap(), format);
+            let decoded = Thumbnail::decode_image(&data).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (8, 4), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn make_thumbnail_as_encodes_every_supported_format() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+
+        for format in ThumbnailFormat::supported() {
+            let target = dir.path().join(format!("thumb.{}", format.extension()));
+            Thumbnail::make_thumbnail_as(&source, &target, *format).unwrap();
+
+            let bytes = std::fs::read(&target).unwrap();
+            assert_eq!(
+                image::guess_format(&bytes).ok(),
+                image::ImageFormat::from_extension(format.extension())
+            );
+        }
+    }
+
+    #[test]
+    fn normalizes_quality() {
+        assert_eq!(ThumbnailFormat::Jpeg.normalize_quality(None), 80);
+        assert_eq!(ThumbnailFormat::Jpeg.normalize_quality(Some(83)), 85);
+        assert_eq!(ThumbnailFormat::Jpeg.normalize_quality(Some(0)), 5);
+        assert_eq!(ThumbnailFormat::Jpeg.normalize_quality(Some(255)), 100);
+        assert_eq!(ThumbnailFormat::Png.normalize_quality(Some(10)), 100);
+        assert_eq!(ThumbnailFormat::WebP.normalize_quality(None), 100);
+    }
+
+    #[test]
+    fn make_thumbnail_as_keeps_aspect_ratio() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        let target = dir.path().join("thumb.webp");
+        write_source_image(&source);
+
+        Thumbnail::make_thumbnail_as(&source, &target, ThumbnailFormat::WebP).unwrap();
+
+        let decoded = image::open(&target).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 67));
+    }
+
+    #[test]
+    fn optimized_thumbnail_uses_lossless_encoding_for_flat_images() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+
+        let encoded = Thumbnail::make_thumbnail_optimized(
+            &source,
+            &ThumbnailOptions::default(),
+            &OptimizeOptions::default(),
+        )
+        .unwrap();
+
+        assert_ne!(encoded.format, ThumbnailFormat::Jpeg);
+        let decoded = image::load_from_memory(&encoded.data).unwrap().to_rgba8();
+        assert!(decoded.pixels().all(|p| p.0 == [200, 40, 40, 255]));
+    }
+
+    #[test]
+    fn optimized_thumbnail_keeps_requested_format_for_photos() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("noise.png");
+        RgbaImage::from_fn(200, 200, |x, y| {
+            Rgba([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x + y) % 256) as u8,
+                255,
+            ])
+        })
+        .save(&source)
+        .unwrap();
+
+        let encoded = Thumbnail::make_thumbnail_optimized(
+            &source,
+            &ThumbnailOptions::default(),
+            &OptimizeOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(encoded.format, ThumbnailFormat::Jpeg);
+    }
+
+    #[test]
+    fn optimized_and_quantized_thumbnails_honour_size_and_fit() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+        let options = ThumbnailOptions {
+            width: 60,
+            height: 60,
+            fit: Fit::Cover,
+            format: ThumbnailFormat::Png,
+            quality: 80,
+        };
+
+        let optimized =
+            Thumbnail::make_thumbnail_optimized(&source, &options, &OptimizeOptions::default())
+                .unwrap();
+        let decoded = image::load_from_memory(&optimized.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (60, 60));
+
+        let quantized =
+            Thumbnail::make_thumbnail_quantized(&source, &options, &QuantizeOptions::default())
+                .unwrap();
+        let decoded = image::load_from_memory(&quantized.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (60, 60));
+    }
+
+    #[test]
+    fn variants_honour_fit_mode() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+
+        let size_for = |fit| {
+            let options = ThumbnailOptions {
+                width: 60,
+                height: 60,
+                fit,
+                format: ThumbnailFormat::Png,
+                quality: 80,
+            };
+            let encoded = Thumbnail::make_variant(&source, &options).unwrap();
+            let decoded = image::load_from_memory(&encoded.data).unwrap();
+            (decoded.width(), decoded.height())
+        };
+
+        assert_eq!(size_for(Fit::Contain), (60, 40));
+        assert_eq!(size_for(Fit::Cover), (60, 60));
+        assert_eq!(size_for(Fit::Fill), (60, 60));
+    }
+
+    #[test]
+    fn make_variants_renders_each_option_in_order() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+        let options = [
+            ThumbnailOptions::default(),
+            ThumbnailOptions {
+                width: 30,
+                height: 30,
+                fit: Fit::Cover,
+                format: ThumbnailFormat::Png,
+                quality: 100,
+            },
+        ];
+
+        let variants = Thumbnail::make_variants(&source, &options).unwrap();
+
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].format, ThumbnailFormat::Jpeg);
+        let second = image::load_from_memory(&variants[1].data).unwrap();
+        assert_eq!((second.width(), second.height()), (30, 30));
+    }
+
+    #[test]
+    fn jpeg_quality_changes_output_size() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("noise.png");
+        RgbaImage::from_fn(200, 200, |x, y| {
+            Rgba([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x * y) % 256) as u8,
+                255,
+            ])
+        })
+        .save(&source)
+        .unwrap();
+
+        let encode = |quality| {
+            let options = ThumbnailOptions {
+                width: 200,
+                height: 200,
+                quality,
+                ..ThumbnailOptions::default()
+            };
+            Thumbnail::make_variant(&source, &options)
+                .unwrap()
+                .data
+                .len()
+        };
+
+        assert!(encode(20) < encode(95));
+    }
+
+    #[test]
+    fn missing_source_is_reported_as_not_found() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("missing.png");
+        let target = dir.path().join("thumb.jpg");
+
+        let err =
+            Thumbnail::make_thumbnail_as(&source, &target, ThumbnailFormat::Jpeg).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ThumbnailError>(),
+            Some(ThumbnailError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn variants_can_be_made_from_memory() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        write_source_image(&source);
+        let data = std::fs::read(&source).unwrap();
+        let options = ThumbnailOptions {
+            format: ThumbnailFormat::Png,
+            ..ThumbnailOptions::default()
+        };
+
+        let from_memory = Thumbnail::make_variants_from_memory(&data, &[options]).unwrap();
+
+        assert_eq!(
+            from_memory,
+            Thumbnail::make_variants(&source, &[options]).unwrap()
+        );
+        assert!(Thumbnail::make_variants_from_memory(b"not an image", &[options]).is_err());
+    }
+
+    #[test]
+    fn format_lookup_by_mime_type() {
+        assert_eq!(
+            ThumbnailFormat::from_mime_type("image/webp"),
+            Some(ThumbnailFormat::WebP)
+        );
+        assert_eq!(ThumbnailFormat::from_mime_type("image/gif"), None);
+    }
 }
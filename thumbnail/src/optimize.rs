@@ -0,0 +1,101 @@
+// Lossless optimization for thumbnails of screenshots, diagrams and other
+// images with flat colours, where PNG or lossless WebP beat JPEG on both
+// size and quality.
+use std::collections::HashSet;
+
+use image::DynamicImage;
+
+/// Images with at most this many distinct colours are encoded losslessly.
+pub const DEFAULT_MAX_COLORS: usize = 256;
+
+/// Tuning for the optimization pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeOptions {
+    /// Colour count up to which an image counts as "few colours".
+    pub max_colors: usize,
+    /// oxipng preset, 0 (fast) to 6 (smallest).
+    pub level: u8,
+    /// Use the much slower zopfli deflater for the final recompression.
+    pub zopfli: bool,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            max_colors: DEFAULT_MAX_COLORS,
+            level: 2,
+            zopfli: false,
+        }
+    }
+}
+
+/// Counts the distinct RGBA colours of `image`, giving up with `None` as soon
+/// as there are more than `limit`.
+pub fn count_colors(image: &DynamicImage, limit: usize) -> Option<usize> {
+    let mut colors = HashSet::with_capacity(limit.min(4096) + 1);
+    for pixel in image.to_rgba8().pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > limit {
+            return None;
+        }
+    }
+    Some(colors.len())
+}
+
+/// Recompresses a PNG with palette, bit-depth and colour-type reduction and a
+/// search over row filters. Returns the input unchanged if nothing is gained.
+#[cfg(feature = "optimize")]
+pub fn optimize_png(data: &[u8], options: &OptimizeOptions) -> anyhow::Result<Vec<u8>> {
+    let mut oxipng_options = oxipng::Options::from_preset(options.level);
+    oxipng_options.strip = oxipng::StripChunks::Safe;
+    if options.zopfli {
+        oxipng_options.deflater = oxipng::Deflater::Zopfli(oxipng::ZopfliOptions::default());
+    }
+
+    match oxipng::optimize_from_memory(data, &oxipng_options) {
+        Ok(optimized) if optimized.len() < data.len() => Ok(optimized),
+        Ok(_) => Ok(data.to_vec()),
+        Err(e) => Err(crate::ThumbnailError::Processing(e.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    fn striped_image(colors: u8) -> DynamicImage {
+        let image = RgbImage::from_fn(64, 64, |x, _| {
+            let band = (x as u8) % colors;
+            Rgb([band * 10, 255 - band * 10, 128])
+        });
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn count_colors_reports_exact_count_under_limit() {
+        assert_eq!(count_colors(&striped_image(4), 256), Some(4));
+    }
+
+    #[test]
+    fn count_colors_gives_up_over_limit() {
+        assert_eq!(count_colors(&striped_image(20), 16), None);
+    }
+
+    #[cfg(feature = "optimize")]
+    #[test]
+    fn optimize_png_never_grows_the_file() {
+        let mut png = Vec::new();
+        striped_image(4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let optimized = optimize_png(&png, &OptimizeOptions::default()).unwrap();
+        assert!(optimized.len() <= png.len());
+        assert_eq!(
+            image::load_from_memory(&optimized).unwrap().to_rgb8(),
+            striped_image(4).to_rgb8()
+        );
+    }
+}
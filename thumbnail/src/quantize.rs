@@ -0,0 +1,245 @@
+// Colour quantization for tiny UI thumbnails: reduces an image to a palette of
+// at most 256 colours and writes it as an 8-bit indexed PNG or GIF.
+use std::borrow::Cow;
+
+use color_quant::NeuQuant;
+use image::imageops::{dither, index_colors};
+use image::DynamicImage;
+
+use crate::ThumbnailError;
+
+/// Indexed output containers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PaletteFormat {
+    Png,
+    Gif,
+}
+
+impl PaletteFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PaletteFormat::Png => "png",
+            PaletteFormat::Gif => "gif",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            PaletteFormat::Png => "image/png",
+            PaletteFormat::Gif => "image/gif",
+        }
+    }
+}
+
+/// Parameters for palette-based output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizeOptions {
+    /// Upper bound for the palette size, clamped to 2..=256.
+    pub max_colors: usize,
+    /// Apply Floyd-Steinberg error diffusion when mapping pixels to the palette.
+    pub dither: bool,
+    pub format: PaletteFormat,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            max_colors: 256,
+            dither: false,
+            format: PaletteFormat::Png,
+        }
+    }
+}
+
+/// How much quantization saved compared to a true-colour PNG of the same image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizeReport {
+    pub colors: usize,
+    pub original_size: usize,
+    pub quantized_size: usize,
+}
+
+impl QuantizeReport {
+    /// Bytes saved; zero if the palette output turned out larger.
+    pub fn bytes_saved(&self) -> usize {
+        self.original_size.saturating_sub(self.quantized_size)
+    }
+
+    /// Savings as a fraction of the true-colour size, between 0.0 and 1.0.
+    pub fn savings_ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            return 0.0;
+        }
+        self.bytes_saved() as f64 / self.original_size as f64
+    }
+}
+
+/// A palettized image together with its container format and size report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizedImage {
+    pub format: PaletteFormat,
+    pub data: Vec<u8>,
+    pub report: QuantizeReport,
+}
+
+// NeuQuant sampling factor: 1 is slowest and best, 30 fastest. Thumbnails are
+// small enough that a thorough pass is cheap.
+const SAMPLE_FACTOR: i32 = 3;
+
+/// Reduces `image` to at most `options.max_colors` colours and encodes it as
+/// an indexed PNG or GIF.
+pub fn quantize(image: &DynamicImage, options: &QuantizeOptions) -> anyhow::Result<QuantizedImage> {
+    let max_colors = options.max_colors.clamp(2, 256);
+    let mut rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    let quantizer = NeuQuant::new(SAMPLE_FACTOR, max_colors, rgba.as_raw());
+    if options.dither {
+        dither(&mut rgba, &quantizer);
+    }
+    let indices = index_colors(&rgba, &quantizer).into_raw();
+    let palette = quantizer.color_map_rgba();
+
+    let data = match options.format {
+        PaletteFormat::Png => encode_indexed_png(width, height, &palette, &indices)?,
+        PaletteFormat::Gif => encode_indexed_gif(width, height, &palette, &indices)?,
+    };
+
+    let mut original = Vec::new();
+    image.write_to(
+        &mut std::io::Cursor::new(&mut original),
+        image::ImageFormat::Png,
+    )?;
+
+    Ok(QuantizedImage {
+        format: options.format,
+        report: QuantizeReport {
+            colors: palette.len() / 4,
+            original_size: original.len(),
+            quantized_size: data.len(),
+        },
+        data,
+    })
+}
+
+fn encode_indexed_png(
+    width: u32,
+    height: u32,
+    palette: &[u8],
+    indices: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let rgb: Vec<u8> = palette
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    let alpha: Vec<u8> = palette.chunks_exact(4).map(|c| c[3]).collect();
+
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::High);
+        encoder.set_palette(rgb);
+        if alpha.iter().any(|a| *a < u8::MAX) {
+            encoder.set_trns(alpha);
+        }
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(indices)?;
+        writer.finish()?;
+    }
+    Ok(data)
+}
+
+fn encode_indexed_gif(
+    width: u32,
+    height: u32,
+    palette: &[u8],
+    indices: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(ThumbnailError::Processing("image too large for GIF".to_string()).into());
+    };
+
+    let rgb: Vec<u8> = palette
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    // GIF only knows a single fully transparent palette entry.
+    let transparent = palette
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(_, c)| c[3] < 128)
+        .min_by_key(|(_, c)| c[3])
+        .map(|(index, _)| index as u8);
+
+    let mut data = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut data, width, height, &rgb)?;
+        let frame = gif::Frame {
+            width,
+            height,
+            transparent,
+            buffer: Cow::Borrowed(indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame)?;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgba, RgbaImage};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+        }))
+    }
+
+    fn noise() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            let mut seed = x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663);
+            seed ^= seed >> 13;
+            seed = seed.wrapping_mul(0x5bd1_e995);
+            seed ^= seed >> 15;
+            Rgba([seed as u8, (seed >> 8) as u8, (seed >> 16) as u8, 255])
+        }))
+    }
+
+    #[test]
+    fn palette_never_exceeds_max_colors() {
+        let options = QuantizeOptions {
+            max_colors: 16,
+            ..QuantizeOptions::default()
+        };
+
+        let quantized = quantize(&gradient(), &options).unwrap();
+
+        assert!(quantized.report.colors <= 16);
+        let decoded = image::load_from_memory(&quantized.data).unwrap().to_rgba8();
+        let distinct: std::collections::HashSet<_> = decoded.pixels().map(|p| p.0).collect();
+        assert!(distinct.len() <= 16);
+    }
+
+    #[test]
+    fn dithered_gif_decodes_and_reports_savings() {
+        let options = QuantizeOptions {
+            max_colors: 8,
+            dither: true,
+            format: PaletteFormat::Gif,
+        };
+
+        let quantized = quantize(&noise(), &options).unwrap();
+
+        assert_eq!(
+            image::guess_format(&quantized.data).unwrap(),
+            image::ImageFormat::Gif
+        );
+        assert!(quantized.report.quantized_size < quantized.report.original_size);
+        assert!(quantized.report.savings_ratio() > 0.0);
+    }
+}
@@ -0,0 +1,411 @@
+// Deep Zoom (DZI) tile pyramids for pan-and-zoom viewing of very large scans.
+//
+// Level `max_level()` is the full-resolution image, every level below halves
+// it, down to a single pixel at level 0. Each level is cut into square tiles
+// of `tile_size` pixels that overlap their neighbours by `overlap` pixels.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::{Thumbnail, ThumbnailError, ThumbnailFormat};
+
+/// Layout and encoding of the generated tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileOptions {
+    pub tile_size: u32,
+    pub overlap: u32,
+    pub format: ThumbnailFormat,
+}
+
+impl Default for TileOptions {
+    // The defaults of the Deep Zoom tools, which viewers are tuned for.
+    fn default() -> Self {
+        Self {
+            tile_size: 254,
+            overlap: 1,
+            format: ThumbnailFormat::Jpeg,
+        }
+    }
+}
+
+/// Pixel rectangle of a tile within its level, overlap included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Geometry of the pyramid for an image of a given size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TilePyramid {
+    pub width: u32,
+    pub height: u32,
+    pub options: TileOptions,
+}
+
+impl TilePyramid {
+    pub fn new(width: u32, height: u32, options: TileOptions) -> Self {
+        Self {
+            width: width.max(1),
+            height: height.max(1),
+            options,
+        }
+    }
+
+    /// Index of the full-resolution level.
+    pub fn max_level(&self) -> u32 {
+        let largest = self.width.max(self.height);
+        u32::BITS - (largest - 1).leading_zeros()
+    }
+
+    /// Size of the whole image at `level`.
+    pub fn level_size(&self, level: u32) -> Option<(u32, u32)> {
+        let max_level = self.max_level();
+        if level > max_level {
+            return None;
+        }
+        let scale = 1u64 << (max_level - level);
+        let scaled = |size: u32| (size as u64).div_ceil(scale).max(1) as u32;
+        Some((scaled(self.width), scaled(self.height)))
+    }
+
+    /// Number of tile columns and rows at `level`.
+    pub fn tile_grid(&self, level: u32) -> Option<(u32, u32)> {
+        let (width, height) = self.level_size(level)?;
+        let tile_size = self.options.tile_size.max(1);
+        Some((width.div_ceil(tile_size), height.div_ceil(tile_size)))
+    }
+
+    /// Rectangle covered by the tile at `col`/`row`, or `None` outside the grid.
+    pub fn tile_rect(&self, level: u32, col: u32, row: u32) -> Option<TileRect> {
+        let (width, height) = self.level_size(level)?;
+        let (cols, rows) = self.tile_grid(level)?;
+        if col >= cols || row >= rows {
+            return None;
+        }
+
+        let tile_size = self.options.tile_size.max(1);
+        let overlap = self.options.overlap;
+        let span = |index: u32, size: u32| {
+            let start = (index * tile_size).saturating_sub(if index > 0 { overlap } else { 0 });
+            let end = ((index + 1) * tile_size + overlap).min(size);
+            (start, end - start)
+        };
+        let (x, tile_width) = span(col, width);
+        let (y, tile_height) = span(row, height);
+
+        Some(TileRect {
+            x,
+            y,
+            width: tile_width,
+            height: tile_height,
+        })
+    }
+
+    /// The `.dzi` XML descriptor understood by OpenSeadragon and other viewers.
+    pub fn dzi_descriptor(&self) -> String {
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                "\n",
+                r#"<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="{}" Overlap="{}" TileSize="{}">"#,
+                "\n",
+                r#"  <Size Width="{}" Height="{}"/>"#,
+                "\n</Image>\n"
+            ),
+            self.options.format.extension(),
+            self.options.overlap,
+            self.options.tile_size,
+            self.width,
+            self.height,
+        )
+    }
+
+    /// Renders a single tile straight from the full-resolution image, for lazy
+    /// generation where building the whole pyramid up front would be wasteful.
+    pub fn render_tile(
+        &self,
+        image: &DynamicImage,
+        level: u32,
+        col: u32,
+        row: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (Some(rect), Some((level_width, level_height))) =
+            (self.tile_rect(level, col, row), self.level_size(level))
+        else {
+            return Ok(None);
+        };
+
+        // Map the tile back onto the source pixels it was scaled down from.
+        let scale_x = image.width() as f64 / level_width as f64;
+        let scale_y = image.height() as f64 / level_height as f64;
+        let source_x = (rect.x as f64 * scale_x).floor() as u32;
+        let source_y = (rect.y as f64 * scale_y).floor() as u32;
+        let source_width = ((rect.width as f64 * scale_x).ceil() as u32)
+            .clamp(1, image.width().saturating_sub(source_x).max(1));
+        let source_height = ((rect.height as f64 * scale_y).ceil() as u32)
+            .clamp(1, image.height().saturating_sub(source_y).max(1));
+
+        let tile = image
+            .crop_imm(source_x, source_y, source_width, source_height)
+            .resize_exact(rect.width, rect.height, FilterType::Triangle);
+        Thumbnail::encode_to_vec(&tile, self.options.format).map(Some)
+    }
+
+    /// Path of a tile below the `_files` directory of a pyramid.
+    pub fn tile_path(&self, tiles_dir: &Path, level: u32, col: u32, row: u32) -> PathBuf {
+        tiles_dir
+            .join(level.to_string())
+            .join(format!("{col}_{row}.{}", self.options.format.extension()))
+    }
+}
+
+/// Reads only the header of the image at `file_path` to lay out its pyramid.
+pub fn read_tile_pyramid(file_path: &Path, options: TileOptions) -> anyhow::Result<TilePyramid> {
+    let reader = image::ImageReader::open(file_path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            ThumbnailError::NotFound(file_path.to_string_lossy().into_owned())
+        } else {
+            ThumbnailError::Processing("Error processing file".to_string())
+        }
+    })?;
+    let (width, height) = reader.with_guessed_format()?.into_dimensions()?;
+    Ok(TilePyramid::new(width, height, options))
+}
+
+/// Renders one tile of the image at `file_path`. Returns `Ok(None)` when the
+/// tile coordinates lie outside the pyramid.
+pub fn render_tile_from_file(
+    file_path: &Path,
+    level: u32,
+    col: u32,
+    row: u32,
+    options: TileOptions,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let pyramid = read_tile_pyramid(file_path, options)?;
+    if pyramid.tile_rect(level, col, row).is_none() {
+        return Ok(None);
+    }
+    let image = Thumbnail::load_image(file_path)?;
+    pyramid.render_tile(&image, level, col, row)
+}
+
+/// Like [`read_tile_pyramid`], for an encoded image already in memory.
+pub fn read_tile_pyramid_from_memory(
+    data: &[u8],
+    options: TileOptions,
+) -> anyhow::Result<TilePyramid> {
+    let reader = image::ImageReader::new(std::io::Cursor::new(data));
+    let (width, height) = reader.with_guessed_format()?.into_dimensions()?;
+    Ok(TilePyramid::new(width, height, options))
+}
+
+/// Like [`render_tile_from_file`], for an encoded image already in memory.
+pub fn render_tile_from_memory(
+    data: &[u8],
+    level: u32,
+    col: u32,
+    row: u32,
+    options: TileOptions,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let pyramid = read_tile_pyramid_from_memory(data, options)?;
+    if pyramid.tile_rect(level, col, row).is_none() {
+        return Ok(None);
+    }
+    let image = Thumbnail::decode_image(data)?;
+    pyramid.render_tile(&image, level, col, row)
+}
+
+/// Directory holding the tiles of the descriptor at `dzi_path`, following the
+/// Deep Zoom convention `name.dzi` + `name_files/`.
+pub fn tiles_dir(dzi_path: &Path) -> PathBuf {
+    let stem = dzi_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dzi_path.with_file_name(format!("{stem}_files"))
+}
+
+/// Builds the complete pyramid for the image at `file_path`, writing the
+/// descriptor to `dzi_path` and the tiles to `<name>_files/<level>/<col>_<row>.<ext>`.
+pub fn generate_tile_pyramid(
+    file_path: &Path,
+    dzi_path: &Path,
+    options: TileOptions,
+) -> anyhow::Result<TilePyramid> {
+    let image = Thumbnail::load_image(file_path)?;
+    let tiles_dir = tiles_dir(dzi_path);
+    let pyramid = cut_pyramid(image, options, |pyramid, level, col, row, data| {
+        let path = pyramid.tile_path(&tiles_dir, level, col, row);
+        if let Some(level_dir) = path.parent() {
+            fs::create_dir_all(level_dir)?;
+        }
+        fs::write(path, data)?;
+        Ok(())
+    })?;
+
+    fs::write(dzi_path, pyramid.dzi_descriptor())?;
+    Ok(pyramid)
+}
+
+/// Decodes `data` once and renders every tile of its pyramid, handing each
+/// tile to `sink` with its level, column and row. Tiles come level by level
+/// from full resolution down, so level 0 is always the last.
+pub fn render_tile_pyramid<F>(
+    data: &[u8],
+    options: TileOptions,
+    mut sink: F,
+) -> anyhow::Result<TilePyramid>
+where
+    F: FnMut(u32, u32, u32, Vec<u8>) -> anyhow::Result<()>,
+{
+    let image = Thumbnail::decode_image(data)?;
+    cut_pyramid(image, options, |_, level, col, row, data| {
+        sink(level, col, row, data)
+    })
+}
+
+// Walks from full resolution down, halving the previous level each time
+// rather than scaling every level from the original.
+fn cut_pyramid<F>(
+    image: DynamicImage,
+    options: TileOptions,
+    mut sink: F,
+) -> anyhow::Result<TilePyramid>
+where
+    F: FnMut(&TilePyramid, u32, u32, u32, Vec<u8>) -> anyhow::Result<()>,
+{
+    let pyramid = TilePyramid::new(image.width(), image.height(), options);
+    let mut level_image = image;
+    for level in (0..=pyramid.max_level()).rev() {
+        let (width, height) = pyramid.level_size(level).unwrap_or((1, 1));
+        if (level_image.width(), level_image.height()) != (width, height) {
+            level_image = level_image.resize_exact(width, height, FilterType::Triangle);
+        }
+
+        let (cols, rows) = pyramid.tile_grid(level).unwrap_or((1, 1));
+        for row in 0..rows {
+            for col in 0..cols {
+                let Some(rect) = pyramid.tile_rect(level, col, row) else {
+                    continue;
+                };
+                let tile = level_image.crop_imm(rect.x, rect.y, rect.width, rect.height);
+                let data = Thumbnail::encode_to_vec(&tile, options.format)?;
+                sink(&pyramid, level, col, row, data)?;
+            }
+        }
+    }
+    Ok(pyramid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+    use tempfile::tempdir;
+
+    fn pyramid(width: u32, height: u32) -> TilePyramid {
+        TilePyramid::new(width, height, TileOptions::default())
+    }
+
+    #[test]
+    fn levels_halve_down_to_a_single_pixel() {
+        let pyramid = pyramid(1000, 600);
+
+        assert_eq!(pyramid.max_level(), 10);
+        assert_eq!(pyramid.level_size(10), Some((1000, 600)));
+        assert_eq!(pyramid.level_size(9), Some((500, 300)));
+        assert_eq!(pyramid.level_size(0), Some((1, 1)));
+        assert_eq!(pyramid.level_size(11), None);
+    }
+
+    #[test]
+    fn tiles_overlap_inner_edges_only() {
+        let pyramid = pyramid(1000, 600);
+
+        assert_eq!(pyramid.tile_grid(10), Some((4, 3)));
+        assert_eq!(
+            pyramid.tile_rect(10, 0, 0),
+            Some(TileRect {
+                x: 0,
+                y: 0,
+                width: 255,
+                height: 255
+            })
+        );
+        assert_eq!(
+            pyramid.tile_rect(10, 1, 2),
+            Some(TileRect {
+                x: 253,
+                y: 507,
+                width: 256,
+                height: 93
+            })
+        );
+        assert_eq!(pyramid.tile_rect(10, 4, 0), None);
+    }
+
+    #[test]
+    fn generated_pyramid_matches_lazily_rendered_tiles() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("scan.png");
+        RgbImage::from_fn(600, 300, |x, y| Rgb([(x % 256) as u8, (y % 256) as u8, 0]))
+            .save(&source)
+            .unwrap();
+        let dzi_path = dir.path().join("scan.dzi");
+
+        let pyramid = generate_tile_pyramid(&source, &dzi_path, TileOptions::default()).unwrap();
+
+        let descriptor = fs::read_to_string(&dzi_path).unwrap();
+        assert!(descriptor.contains(r#"<Size Width="600" Height="300"/>"#));
+        let tiles_dir = tiles_dir(&dzi_path);
+        for level in 0..=pyramid.max_level() {
+            let (cols, rows) = pyramid.tile_grid(level).unwrap();
+            for row in 0..rows {
+                for col in 0..cols {
+                    let path = pyramid.tile_path(&tiles_dir, level, col, row);
+                    let rect = pyramid.tile_rect(level, col, row).unwrap();
+                    let tile = image::open(&path).unwrap();
+                    assert_eq!((tile.width(), tile.height()), (rect.width, rect.height));
+                }
+            }
+        }
+
+        let lazy = render_tile_from_file(&source, 9, 1, 0, TileOptions::default())
+            .unwrap()
+            .unwrap();
+        let lazy = image::load_from_memory(&lazy).unwrap();
+        let rect = pyramid.tile_rect(9, 1, 0).unwrap();
+        assert_eq!((lazy.width(), lazy.height()), (rect.width, rect.height));
+        assert_eq!(
+            render_tile_from_file(&source, 9, 2, 0, TileOptions::default()).unwrap(),
+            None
+        );
+
+        let data = fs::read(&source).unwrap();
+        let from_memory = render_tile_from_memory(&data, 9, 1, 0, TileOptions::default()).unwrap();
+        assert_eq!(
+            from_memory,
+            render_tile_from_file(&source, 9, 1, 0, TileOptions::default()).unwrap()
+        );
+
+        let mut rendered = Vec::new();
+        let from_memory =
+            render_tile_pyramid(&data, TileOptions::default(), |level, col, row, data| {
+                rendered.push((level, col, row, data));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(from_memory, pyramid);
+        assert_eq!(rendered.last().map(|tile| tile.0), Some(0));
+        for (level, col, row, data) in rendered {
+            let path = pyramid.tile_path(&tiles_dir, level, col, row);
+            assert_eq!(data, fs::read(path).unwrap());
+        }
+    }
+}